@@ -1,101 +1,416 @@
-use std::path::PathBuf;
-
-const HELP: &str = "
-collate v1 by @jakintosh
+use collate::{diagnostic::Renderer, Indent, Library, Options};
+use std::{
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
+const HELP: &str = concat!(
+    "\ncollate v",
+    env!("CARGO_PKG_VERSION"),
+    " by @jakintosh\n",
+    "
 USAGE:
-    collate <source_dir> <output_dir> [--verbose | --quiet]";
+    collate <command> [arguments] [options]
+    collate <source_dir> <output_dir> [options]    (same as `build`)
+
+COMMANDS:
+    build <source_dir> <output_dir>    Export every file export to <output_dir>
+    check <source_dir>                 Validate the library without writing files
+    render <source_dir> <block> [args...]
+                                       Print a single block to stdout, passing
+                                       each arg to its params in order
+    list <source_dir>                  List every block with its params and export
+    graph <source_dir>                 Print the block dependency graph as DOT
+    fmt <source_dir> [--check]         Normalize the spacing inside commands
+    init <dir>                         Create a new source directory
+
+OPTIONS:
+    -v, --verbose             Print each exported file
+    -q, --quiet               Print nothing but errors
+    --strict                  Report ignored source, unclosed blocks and unused params
+    --generation-limit <n>    Passes of block exports to allow (default 32)
+    --dump-generated <dir>    Write the source generated by each pass to <dir>
+    --indent <tabs|n>         Indent with tabs or n spaces where files don't say (default tabs)
+    --check                   With `fmt`, list unformatted files instead of fixing them
+    -o, --output <file>       With `render`, write to <file> instead of stdout
+    -h, --help                Print this message
+    --version                 Print the version"
+);
 
-const VERSION: &str = "
-collate v1 by @jakintosh";
+const VERSION: &str = concat!("\ncollate v", env!("CARGO_PKG_VERSION"), " by @jakintosh");
 
-enum Parameters {
-    Run {
+const INIT_FILE: &str = "index.txt";
+const INIT_SOURCE: &str = "^|n index|
+^|x index.html|
+<!DOCTYPE html>
+<html>
+	<body>
+		^|ui greeting (world)|
+	</body>
+</html>
+^|e|
+
+^|n greeting|
+^|p name|
+<p>Hello, ^|u #name|!</p>
+^|e|
+";
+
+enum Command {
+    Build {
         source: PathBuf,
         output: PathBuf,
-        quiet: bool,
-        verbose: bool,
+    },
+    Check {
+        source: PathBuf,
+    },
+    Render {
+        source: PathBuf,
+        block: String,
+        args: Vec<String>,
+    },
+    List {
+        source: PathBuf,
+    },
+    Graph {
+        source: PathBuf,
+    },
+    Fmt {
+        source: PathBuf,
+        check: bool,
+    },
+    Init {
+        dir: PathBuf,
     },
     Help,
     Version,
 }
+
+#[derive(Default)]
+struct Flags {
+    quiet: bool,
+    verbose: bool,
+    check: bool,
+    output: Option<PathBuf>,
+    options: Options,
+}
+
+struct Parameters {
+    command: Command,
+    flags: Flags,
+}
 impl TryFrom<std::env::Args> for Parameters {
     type Error = String;
 
     fn try_from(mut args: std::env::Args) -> Result<Self, Self::Error> {
         args.next(); // skip first arg, bin location
 
-        let source = match args.next() {
-            Some(arg) => match arg.as_str() {
-                "--help" | "-h" => return Ok(Parameters::Help),
-                "--version" => return Ok(Parameters::Version),
-                _ => arg,
-            },
-            None => return Err(String::from("Missing `source_dir` argument")),
-        };
-        let output = match args.next() {
-            Some(arg) => match arg.as_str() {
-                "--help" | "-h" => return Ok(Parameters::Help),
-                "--version" => return Ok(Parameters::Version),
-                _ => arg,
-            },
-            None => return Err(String::from("Missing `output_dir` argument")),
-        };
-        let source = source.into();
-        let output = output.into();
-
-        let mut quiet = false;
-        let mut verbose = false;
+        let mut flags = Flags::default();
+        let mut positional = Vec::new();
         while let Some(arg) = args.next() {
+            let command = match arg.as_str() {
+                "--help" | "-h" => Some(Command::Help),
+                "--version" => Some(Command::Version),
+                _ => None,
+            };
+            if let Some(command) = command {
+                return Ok(Parameters { command, flags });
+            }
             match arg.as_str() {
-                "--quiet" | "-q" => quiet = true,
-                "--verbose" | "-v" => verbose = true,
-                _ => panic!("unrecognized parameter: {}\n{}", arg, HELP),
+                "--quiet" | "-q" => flags.quiet = true,
+                "--verbose" | "-v" => flags.verbose = true,
+                "--check" => flags.check = true,
+                "--strict" => flags.options.strict = true,
+                "--generation-limit" => {
+                    flags.options.generation_limit = match args.next().map(|n| n.parse()) {
+                        Some(Ok(n)) => n,
+                        _ => return Err(String::from("`--generation-limit` expects a number")),
+                    }
+                }
+                "--output" | "-o" => match args.next() {
+                    Some(file) => flags.output = Some(file.into()),
+                    None => return Err(String::from("`--output` expects a file")),
+                },
+                "--indent" => {
+                    flags.options.indent = match args.next().as_deref() {
+                        Some("tabs") => Indent::Tabs,
+                        width => match width.map(str::parse) {
+                            Some(Ok(width)) if width > 0 => Indent::Spaces(width),
+                            _ => return Err(String::from("`--indent` expects `tabs` or a width")),
+                        },
+                    }
+                }
+                "--dump-generated" => match args.next() {
+                    Some(dir) => flags.options.dump_generated = Some(dir.into()),
+                    None => return Err(String::from("`--dump-generated` expects a directory")),
+                },
+                "--" => positional.extend(args.by_ref()),
+                flag if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(format!("Unrecognized option `{}`", flag))
+                }
+                _ => positional.push(arg),
             }
         }
 
-        Ok::<Parameters, String>(Parameters::Run {
-            source,
-            output,
-            quiet,
-            verbose,
-        })
+        let mut positional = positional.into_iter();
+        let mut next = |name: &str| match positional.next() {
+            Some(arg) => Ok(arg),
+            None => Err(format!("Missing `{}` argument", name)),
+        };
+        let command = match next("command")?.as_str() {
+            "build" => Command::Build {
+                source: next("source_dir")?.into(),
+                output: next("output_dir")?.into(),
+            },
+            "check" => Command::Check {
+                source: next("source_dir")?.into(),
+            },
+            "render" => Command::Render {
+                source: next("source_dir")?.into(),
+                block: next("block")?,
+                args: positional.by_ref().collect(),
+            },
+            "list" => Command::List {
+                source: next("source_dir")?.into(),
+            },
+            "graph" => Command::Graph {
+                source: next("source_dir")?.into(),
+            },
+            "fmt" => Command::Fmt {
+                source: next("source_dir")?.into(),
+                check: flags.check,
+            },
+            "init" => Command::Init {
+                dir: next("dir")?.into(),
+            },
+            source => Command::Build {
+                source: source.into(),
+                output: next("output_dir")?.into(),
+            },
+        };
+        if let Some(arg) = positional.next() {
+            return Err(format!("Unexpected argument `{}`", arg));
+        }
+        if flags.check && !matches!(command, Command::Fmt { .. }) {
+            return Err(String::from("`--check` can only be used with `fmt`"));
+        }
+        if flags.output.is_some() && !matches!(command, Command::Render { .. }) {
+            return Err(String::from("`--output` can only be used with `render`"));
+        }
+        if flags.quiet {
+            flags.verbose = false;
+        }
+
+        Ok(Parameters { command, flags })
     }
 }
 
-fn main() {
-    let parameters: Parameters = match std::env::args().try_into() {
+fn main() -> ExitCode {
+    let Parameters { command, flags } = match std::env::args().try_into() {
         Ok(params) => params,
         Err(e) => {
-            println!("{}\n{}", e, HELP);
-            return;
+            eprintln!("{}\n{}", e, HELP);
+            return ExitCode::from(2);
         }
     };
-    let (source, output, quiet, verbose) = match parameters {
-        Parameters::Run {
+    match command {
+        Command::Build { source, output } => build(&source, &output, flags),
+        Command::Check { source } => check(&source, flags),
+        Command::Render {
             source,
-            output,
-            quiet,
-            verbose,
-        } => (source, output, quiet, verbose),
-        Parameters::Help => {
+            block,
+            args,
+        } => render(&source, &block, &args, flags),
+        Command::List { source } => list(&source, flags),
+        Command::Graph { source } => graph(&source, flags),
+        Command::Fmt { source, check } => fmt(&source, check, flags),
+        Command::Init { dir } => init(&dir, flags),
+        Command::Help => {
             println!("{}", HELP);
-            return;
+            ExitCode::SUCCESS
         }
-        Parameters::Version => {
+        Command::Version => {
             println!("{}", VERSION);
-            return;
+            ExitCode::SUCCESS
         }
+    }
+}
+
+fn build(source: &PathBuf, output: &Path, flags: Flags) -> ExitCode {
+    let verbose = flags.verbose;
+    let mut library = match load(source, flags) {
+        Some(library) => library,
+        None => return ExitCode::FAILURE,
     };
-    let mut library = match collate::Library::new_from_dir(&source) {
-        Ok(l) => l,
-        Err(err) => {
-            println!("Parsing failed: {}", err);
-            return;
+    match library.export_all(output, verbose) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => report(&err),
+    }
+}
+
+fn check(source: &PathBuf, flags: Flags) -> ExitCode {
+    let verbose = flags.verbose;
+    let mut library = match load(source, flags) {
+        Some(library) => library,
+        None => return ExitCode::FAILURE,
+    };
+    match library.generate().and_then(|_| library.check()) {
+        Ok(_) => {
+            if verbose {
+                println!("No problems found in '{}'", source.to_string_lossy());
+            }
+            ExitCode::SUCCESS
         }
+        Err(err) => report(&err),
+    }
+}
+
+fn render(source: &PathBuf, block: &str, args: &[String], flags: Flags) -> ExitCode {
+    let output = flags.output.clone();
+    let mut library = match load(source, flags) {
+        Some(library) => library,
+        None => return ExitCode::FAILURE,
+    };
+    let render = match library
+        .generate()
+        .and_then(|_| library.render_with_literals(block, args))
+    {
+        Ok(render) => render,
+        Err(err) => return report(&err),
     };
-    match library.export_all(&output, verbose) {
-        Ok(_) => {}
-        Err(err) => println!("Export failed: {}", err),
+    match output {
+        Some(path) => match fs::write(&path, render) {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(source) => report(&collate::Error::Io { path, source }),
+        },
+        None => {
+            print!("{}", render);
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+fn list(source: &PathBuf, flags: Flags) -> ExitCode {
+    let library = match load(source, flags) {
+        Some(library) => library,
+        None => return ExitCode::FAILURE,
+    };
+    for block in library.blocks() {
+        let export = match (block.file_export(), block.is_block_export()) {
+            (Some(path), _) => format!(" -> {}", path),
+            (None, true) => String::from(" -> (blocks)"),
+            (None, false) => String::new(),
+        };
+        println!(
+            "{}({}){}\t{}",
+            block.name(),
+            block.params().join(", "),
+            export,
+            block.location()
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn graph(source: &PathBuf, flags: Flags) -> ExitCode {
+    fn escape(name: &str) -> String {
+        name.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+    fn quote(name: &str) -> String {
+        format!("\"{}\"", escape(name))
+    }
+
+    let library = match load(source, flags) {
+        Some(library) => library,
+        None => return ExitCode::FAILURE,
+    };
+    println!("digraph collate {{");
+    for block in library.blocks() {
+        match (block.file_export(), block.is_block_export()) {
+            (Some(path), _) => println!(
+                "    {} [shape=box, label=\"{}\\n{}\"];",
+                quote(block.name()),
+                escape(block.name()),
+                escape(path)
+            ),
+            (None, true) => println!("    {} [shape=diamond];", quote(block.name())),
+            (None, false) => println!("    {};", quote(block.name())),
+        }
+        for dependency in block.dependencies() {
+            println!("    {} -> {};", quote(block.name()), quote(dependency));
+        }
+    }
+    println!("}}");
+    ExitCode::SUCCESS
+}
+
+fn fmt(source: &PathBuf, check: bool, flags: Flags) -> ExitCode {
+    let unformatted = match collate::format_dir(source, check) {
+        Ok(unformatted) => unformatted,
+        Err(err) => return report(&err),
     };
+    for path in &unformatted {
+        if check {
+            println!("{}", path.to_string_lossy());
+        } else if flags.verbose {
+            println!("Formatted '{}'", path.to_string_lossy());
+        }
+    }
+    match check && !unformatted.is_empty() {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+fn init(dir: &PathBuf, flags: Flags) -> ExitCode {
+    let occupied = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+    if occupied {
+        eprintln!(
+            "'{}' already exists and is not empty",
+            dir.to_string_lossy()
+        );
+        return ExitCode::FAILURE;
+    }
+    let path = dir.join(INIT_FILE);
+    let created = fs::create_dir_all(dir).and_then(|_| fs::write(&path, INIT_SOURCE));
+    if let Err(source) = created {
+        return report(&collate::Error::Io { path, source });
+    }
+    if !flags.quiet {
+        println!(
+            "Created '{}'; build it with `collate build {} <output_dir>`",
+            path.to_string_lossy(),
+            dir.to_string_lossy()
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn load(source: &PathBuf, flags: Flags) -> Option<Library> {
+    let mut library = Library::with_options(flags.options);
+    let imported = library.import_from_dir(source);
+    if !flags.quiet {
+        let mut renderer = renderer();
+        for warning in library.warnings() {
+            eprintln!("{}", renderer.render_warning(warning));
+        }
+    }
+    match imported {
+        Ok(_) => Some(library),
+        Err(err) => {
+            report(&err);
+            None
+        }
+    }
+}
+
+fn report(err: &collate::Error) -> ExitCode {
+    eprint!("{}", renderer().render(err));
+    ExitCode::FAILURE
+}
+
+fn renderer() -> Renderer {
+    Renderer::new(std::io::stderr().is_terminal())
 }
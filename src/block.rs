@@ -1,35 +1,117 @@
-use std::{collections::HashMap, str::Chars};
-
-const COMMAND_FLAG: char = '^';
-const COMMAND_START: char = '|';
-const COMMAND_END: char = '|';
+use crate::{
+    builtin::{self, BUILTIN_PREFIX},
+    directive::{self, Directive},
+    error::{Error, Location, Position, Span},
+    filter::Filter,
+    lexer::{
+        self, Token, COMMAND_END, COMMENT_COMMAND, COMMENT_PREFIX, PIPE, RAW_COMMAND, TRIM_MARKER,
+    },
+    library::Indent,
+};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 const NEW_BLOCK_COMMAND: &str = "n";
 const DEFINE_PARAMS_COMMAND: &str = "p";
 const FILE_EXPORT_COMMAND: &str = "x";
 const BLOCK_EXPORT_COMMAND: &str = "b";
+const ARGUMENT_SET_COMMAND: &str = "a";
 const USE_BLOCK_COMMAND: &str = "u";
 const USE_BLOCK_INDENTED_COMMAND: &str = "ui";
 const END_BLOCK_COMMAND: &str = "e";
+const CALL_BLOCK_COMMAND: &str = "call";
+const END_CALL_COMMAND: &str = "end";
+const INDENT_COMMAND: &str = "indent";
+const DEFAULT_INDENT_WIDTH: usize = 4;
+const BUILT_IN_COMMANDS: [&str; 13] = [
+    NEW_BLOCK_COMMAND,
+    DEFINE_PARAMS_COMMAND,
+    FILE_EXPORT_COMMAND,
+    BLOCK_EXPORT_COMMAND,
+    ARGUMENT_SET_COMMAND,
+    USE_BLOCK_COMMAND,
+    USE_BLOCK_INDENTED_COMMAND,
+    END_BLOCK_COMMAND,
+    CALL_BLOCK_COMMAND,
+    END_CALL_COMMAND,
+    INDENT_COMMAND,
+    COMMENT_COMMAND,
+    RAW_COMMAND,
+];
 
 #[derive(Clone)]
 pub(crate) struct Block {
     pub name: String,
-    pub param_names: Vec<String>,
+    pub location: Location,
+    pub params: Vec<Param>,
     pub export: Option<Export>,
     pub elements: Vec<Element>,
+    pub indent: Indent,
+    /// Renders the block in Rust instead of from its elements.
+    pub native: Option<Native>,
+    /// The file that defines the block, relative to the directory it was
+    /// imported from.
+    pub source: Option<PathBuf>,
+}
+
+/// What a block is rendered with, beyond its own params.
+pub(crate) struct Context<'a> {
+    pub blocks: &'a HashMap<String, Block>,
+    /// The path of the file being exported, if any.
+    pub export: Option<&'a str>,
+    /// Seconds since the Unix epoch, for built-in blocks that stamp the time.
+    pub time: u64,
+}
+
+/// The arguments a block is used with.
+#[derive(Default)]
+struct Arguments {
+    positional: Vec<Parameter>,
+    named: Vec<(String, Parameter)>,
+}
+
+/// A Rust function that renders a block from its arguments, in param order.
+pub(crate) type Native = Arc<
+    dyn Fn(&[String]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+>;
+
+#[derive(Clone)]
+pub(crate) struct Param {
+    pub name: String,
+    pub default: Option<Argument>,
+    pub span: Span,
 }
 
 pub(crate) enum Component {
-    Open { name: String },
-    Attribute(Attribute),
-    Element(Element),
-    Close,
+    Open {
+        name: String,
+        span: Span,
+    },
+    Attribute(Attribute, Span),
+    Element(Element, Span),
+    OpenCall(Element, Span),
+    CloseCall {
+        span: Span,
+    },
+    Close {
+        span: Span,
+    },
+    /// Sets the indentation of the blocks that follow in the same file.
+    Indent(Indent, Span),
+    Invalid,
 }
 
 pub(crate) enum Attribute {
     Export(Export),
-    ParamName(String),
+    Param {
+        name: String,
+        default: Option<Argument>,
+    },
+    ArgumentSet(ArgumentSet),
 }
 
 #[derive(Clone)]
@@ -48,7 +130,25 @@ pub(crate) enum Parameter {
 #[derive(Clone)]
 pub(crate) enum Export {
     Block,
-    File(String),
+    File {
+        path: String,
+        sets: Vec<ArgumentSet>,
+        span: Span,
+    },
+}
+
+/// Arguments for one file written by a file export.
+#[derive(Clone)]
+pub(crate) struct ArgumentSet {
+    pub arguments: Vec<Argument>,
+    pub span: Span,
+}
+
+/// A file written by a file export, with its path templates filled in.
+pub(crate) struct FileExport {
+    pub path: String,
+    pub parameters: Vec<Parameter>,
+    pub span: Span,
 }
 
 #[derive(Clone)]
@@ -58,6 +158,11 @@ pub(crate) enum Element {
         indented: bool,
         target: Argument,
         arguments: Option<Vec<Argument>>,
+        named: Vec<(String, Argument)>,
+        /// Content passed as the last positional argument by a call.
+        body: Option<Vec<Element>>,
+        filters: Vec<Filter>,
+        span: Span,
     },
 }
 
@@ -65,408 +170,1272 @@ pub(crate) enum Element {
 pub(crate) enum Command {
     Flag(String),
     Argument(Argument),
+    Keyword(String, Argument),
+    Pipe,
 }
 
 impl Block {
-    pub(crate) fn parse(string: &str) -> Result<Vec<Block>, String> {
-        enum State {
-            Content,
-            CommandFlag,
-            Command,
-            SkipNewline,
-            CancelledFlag,
-            InvalidCommand(String),
-        }
+    pub(crate) fn parse(
+        string: &str,
+        path: Option<&Path>,
+        indent: Indent,
+        directives: &HashMap<String, Box<dyn Directive>>,
+        lints: &mut Vec<Error>,
+    ) -> Result<Vec<Block>, Vec<Error>> {
         fn commands_from_str(command_str: &str) -> Result<Vec<Command>, String> {
-            fn read_word(buffer: &mut String, chars: &mut Chars) {
-                while let Some(c) = chars.next() {
-                    match c {
-                        c if c.is_whitespace() => break,
-                        c => buffer.push(c),
-                    }
-                }
-            }
-            let mut chars = command_str.chars();
+            let mut words = lexer::words(command_str).into_iter();
             let mut commands = Vec::new();
-            let mut buffer = String::new();
 
             // get flag
-            read_word(&mut buffer, &mut chars);
-            if buffer.is_empty() {
-                return Err(format!("Couldn't parse command flag from {}", command_str));
+            match words.next() {
+                Some(flag) => commands.push(Command::Flag(flag.to_string())),
+                None => {
+                    return Err(format!(
+                        "Couldn't parse command flag from '{}'",
+                        command_str
+                    ))
+                }
             }
-            commands.push(Command::Flag(buffer.clone()));
-            buffer.clear();
 
             // get arguments
-            while let Some(c) = chars.next() {
-                match c {
-                    '#' => {
-                        read_word(&mut buffer, &mut chars);
-                        commands.push(Command::Argument(Argument::ParamName(buffer.clone())));
-                        buffer.clear();
+            let is_name = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+            for word in words {
+                if word == PIPE {
+                    commands.push(Command::Pipe);
+                    continue;
+                }
+                let command = match word.split_once('=') {
+                    Some((name, value)) if !name.is_empty() && name.chars().all(is_name) => {
+                        Command::Keyword(name.to_string(), argument_from_word(value))
                     }
-                    '(' => {
-                        while let Some(c) = chars.next() {
-                            match c {
-                                ')' => break,
-                                c => buffer.push(c),
-                            }
-                        }
-                        commands.push(Command::Argument(Argument::Literal(buffer.clone())));
-                        buffer.clear();
+                    _ => Command::Argument(argument_from_word(word)),
+                };
+                commands.push(command);
+            }
+            Ok(commands)
+        }
+        fn argument_from_word(word: &str) -> Argument {
+            if let Some(literal) = lexer::literal(word) {
+                return Argument::Literal(literal);
+            }
+            match word.strip_prefix('#') {
+                Some(name) => Argument::ParamName(name.to_string()),
+                None => Argument::Name(word.to_string()),
+            }
+        }
+        fn export_arguments(
+            commands: impl Iterator<Item = Command>,
+        ) -> Result<Vec<Argument>, String> {
+            let mut arguments = Vec::new();
+            for next in commands {
+                match next {
+                    Command::Argument(Argument::ParamName(_)) => {
+                        return Err("Export arguments must be literals or block names".into())
                     }
-                    c if c.is_whitespace() => continue,
-                    c => {
-                        buffer.push(c);
-                        read_word(&mut buffer, &mut chars);
-                        if !buffer.is_empty() {
-                            commands.push(Command::Argument(Argument::Name(buffer.clone())));
-                            buffer.clear();
-                        }
+                    Command::Argument(arg) => arguments.push(arg),
+                    Command::Keyword(..) => {
+                        return Err("Export arguments cannot be given by name".into())
                     }
+                    Command::Pipe => return Err("Exports cannot be filtered".into()),
+                    Command::Flag(_) => {}
                 }
             }
-            Ok(commands)
+            Ok(arguments)
         }
         fn block_components_from_commands(
             commands: Vec<Command>,
+            directives: &HashMap<String, Box<dyn Directive>>,
+            span: Span,
         ) -> Result<Vec<Component>, String> {
             let mut commands = commands.into_iter();
             match commands.next() {
                 Some(Command::Flag(flag)) => match flag.as_str() {
                     NEW_BLOCK_COMMAND => match commands.next() {
+                        Some(Command::Argument(Argument::Name(name)))
+                            if builtin::is_reserved(&name) =>
+                        {
+                            Err(format!(
+                                "Block names starting with '{}' are reserved for built-in blocks",
+                                BUILTIN_PREFIX
+                            ))
+                        }
                         Some(Command::Argument(Argument::Name(name))) => {
-                            Ok(vec![Component::Open { name }])
+                            Ok(vec![Component::Open { name, span }])
                         }
-                        _ => Err(format!("New block command must provide a name")),
+                        _ => Err("New block command must provide a name".into()),
                     },
                     DEFINE_PARAMS_COMMAND => {
                         let mut components = Vec::new();
-                        while let Some(next) = commands.next() {
+                        for next in commands {
                             match next {
                                 Command::Argument(Argument::Name(name)) => {
-                                    let attribute = Attribute::ParamName(name);
-                                    let component = Component::Attribute(attribute);
-                                    components.push(component);
+                                    let attribute = Attribute::Param {
+                                        name,
+                                        default: None,
+                                    };
+                                    components.push(Component::Attribute(attribute, span));
+                                }
+                                Command::Keyword(_, Argument::ParamName(_)) => {
+                                    return Err(
+                                        "Param defaults must be literals or block names".into()
+                                    )
+                                }
+                                Command::Keyword(name, default) => {
+                                    let attribute = Attribute::Param {
+                                        name,
+                                        default: Some(default),
+                                    };
+                                    components.push(Component::Attribute(attribute, span));
                                 }
                                 _ => {
-                                    return Err(format!(
+                                    return Err(
                                         "Define params can only handle Argument::Name commands"
-                                    ))
+                                            .into(),
+                                    )
                                 }
                             }
                         }
                         Ok(components)
                     }
-                    FILE_EXPORT_COMMAND => match commands.next() {
-                        Some(Command::Argument(Argument::Name(path))) => {
-                            let attribute = Attribute::Export(Export::File(path));
-                            let component = Component::Attribute(attribute);
-                            Ok(vec![component])
-                        }
-                        _ => {
-                            return Err(format!(
-                                "Enable Export can only handle Argument::Name commands"
-                            ))
-                        }
-                    },
-                    BLOCK_EXPORT_COMMAND => {
-                        Ok(vec![Component::Attribute(Attribute::Export(Export::Block))])
+                    FILE_EXPORT_COMMAND => {
+                        let path = match commands.next() {
+                            Some(Command::Argument(Argument::Name(path))) => path,
+                            // a path that starts with a template reads as a param name
+                            Some(Command::Argument(Argument::ParamName(name))) => {
+                                format!("#{}", name)
+                            }
+                            _ => {
+                                return Err(
+                                    "Enable Export can only handle Argument::Name commands".into()
+                                )
+                            }
+                        };
+                        let arguments = export_arguments(commands)?;
+                        let sets = match arguments.is_empty() {
+                            true => Vec::new(),
+                            false => vec![ArgumentSet { arguments, span }],
+                        };
+                        let export = Export::File { path, sets, span };
+                        let component = Component::Attribute(Attribute::Export(export), span);
+                        Ok(vec![component])
+                    }
+                    ARGUMENT_SET_COMMAND => {
+                        let arguments = export_arguments(commands)?;
+                        let attribute = Attribute::ArgumentSet(ArgumentSet { arguments, span });
+                        Ok(vec![Component::Attribute(attribute, span)])
                     }
-                    USE_BLOCK_COMMAND | USE_BLOCK_INDENTED_COMMAND => {
+                    BLOCK_EXPORT_COMMAND => Ok(vec![Component::Attribute(
+                        Attribute::Export(Export::Block),
+                        span,
+                    )]),
+                    USE_BLOCK_COMMAND | USE_BLOCK_INDENTED_COMMAND | CALL_BLOCK_COMMAND => {
+                        // calls wrap whole lines, so they are indented like `ui`
                         let indented = match flag.as_str() {
                             USE_BLOCK_COMMAND => false,
-                            USE_BLOCK_INDENTED_COMMAND => true,
+                            USE_BLOCK_INDENTED_COMMAND | CALL_BLOCK_COMMAND => true,
                             _ => unreachable!(),
                         };
                         let target = match commands.next() {
                             Some(Command::Argument(arg)) => arg,
                             _ => {
-                                return Err(format!(
-                                    "Use block expects first command to be argument"
-                                ))
+                                return Err("Use block expects first command to be argument".into())
                             }
                         };
-                        let arguments: Vec<Argument> = commands
-                            .filter_map(|c| match c {
-                                Command::Argument(arg) => Some(arg),
-                                Command::Flag(_) => None,
-                            })
-                            .collect();
+                        let mut arguments = Vec::new();
+                        let mut named = Vec::new();
+                        let mut piped = false;
+                        for next in commands.by_ref() {
+                            match next {
+                                Command::Pipe => {
+                                    piped = true;
+                                    break;
+                                }
+                                Command::Argument(_) if !named.is_empty() => {
+                                    return Err(
+                                        "Positional arguments must come before named arguments"
+                                            .into(),
+                                    )
+                                }
+                                Command::Argument(arg) => arguments.push(arg),
+                                Command::Keyword(name, arg) => named.push((name, arg)),
+                                Command::Flag(_) => {}
+                            }
+                        }
                         let arguments = match arguments.is_empty() {
                             false => Some(arguments),
                             true => None,
                         };
+                        // each `|>` is followed by a filter name and its arguments
+                        let mut filters = Vec::new();
+                        let mut filter: Option<(String, Vec<Argument>)> = None;
+                        let rest = match piped {
+                            true => commands.chain([Command::Pipe]).collect(),
+                            false => Vec::new(),
+                        };
+                        for next in rest {
+                            match (next, filter.take()) {
+                                (Command::Pipe, Some((name, arguments))) => {
+                                    filters.push(Filter::parse(&name, arguments)?)
+                                }
+                                (Command::Argument(Argument::Name(name)), None) => {
+                                    filter = Some((name, Vec::new()))
+                                }
+                                (Command::Argument(arg), Some((name, mut arguments))) => {
+                                    arguments.push(arg);
+                                    filter = Some((name, arguments));
+                                }
+                                _ => return Err("Expected a filter after '|>'".into()),
+                            }
+                        }
+                        let call = flag == CALL_BLOCK_COMMAND;
                         let element = Element::UseBlock {
                             indented,
                             target,
                             arguments,
+                            named,
+                            body: call.then(Vec::new),
+                            filters,
+                            span,
                         };
-                        let component = Component::Element(element);
-                        Ok(vec![component])
+                        match call {
+                            true => Ok(vec![Component::OpenCall(element, span)]),
+                            false => Ok(vec![Component::Element(element, span)]),
+                        }
                     }
-                    END_BLOCK_COMMAND => Ok(vec![Component::Close]),
-                    _ => Err(format!("Unknown Command::Flag '{}'", flag)),
+                    END_CALL_COMMAND => Ok(vec![Component::CloseCall { span }]),
+                    INDENT_COMMAND => {
+                        let words: Vec<String> = commands
+                            .filter_map(|c| match c {
+                                Command::Argument(Argument::Name(word)) => Some(word),
+                                _ => None,
+                            })
+                            .collect();
+                        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+                        let indent = match words[..] {
+                            ["tabs"] => Indent::Tabs,
+                            ["spaces"] => Indent::Spaces(DEFAULT_INDENT_WIDTH),
+                            ["spaces", width] => match width.parse() {
+                                Ok(width) if width > 0 => Indent::Spaces(width),
+                                _ => return Err(format!("Invalid indent width '{}'", width)),
+                            },
+                            _ => {
+                                return Err(
+                                    "Indent expects 'tabs' or 'spaces' with an optional width"
+                                        .into(),
+                                )
+                            }
+                        };
+                        Ok(vec![Component::Indent(indent, span)])
+                    }
+                    END_BLOCK_COMMAND => Ok(vec![Component::Close { span }]),
+                    _ => match directives.get(&flag) {
+                        Some(custom) => directive::components(custom.as_ref(), commands, span),
+                        None => Err(format!("Unknown Command::Flag '{}'", flag)),
+                    },
                 },
-                Some(_) => Err(format!("First command must be a flag")),
-                None => Err(format!("Cannot build block from empty command list")),
+                Some(_) => Err("First command must be a flag".into()),
+                None => Err("Cannot build block from empty command list".into()),
             }
         }
-        fn flush(buffer: &mut String) -> String {
-            let contents = buffer.clone();
-            buffer.clear();
-            contents
-        }
-        fn push_to_state(buffer: &mut String, c: char, state: State) -> State {
-            buffer.push(c);
-            state
-        }
-        fn close_content(buffer: &mut String, components: &mut Vec<Component>) -> State {
-            if !buffer.is_empty() {
-                let content = flush(buffer);
-                let element = Element::Content(content);
-                let component = Component::Element(element);
-                components.push(component);
+
+        let location = |span| Location::new(path.map(Path::to_path_buf), span);
+        let mut components = Vec::new();
+        let mut errors = Vec::new();
+        let mut skip_newline = false;
+        let mut trim_next = false;
+        for lexeme in lexer::lex(string) {
+            let span = lexeme.span;
+            let trim = std::mem::take(&mut trim_next);
+            match lexeme.token {
+                Token::Content(mut content) => {
+                    mixed_indentation(&content, span.start, |span| {
+                        lints.push(Error::MixedIndentation {
+                            location: location(span),
+                        })
+                    });
+                    let mut span = span;
+                    if trim {
+                        // a trim marker removes all of the whitespace that follows
+                        match first_visible(&content, span.start) {
+                            Some(start) => span.start = start,
+                            None => content.clear(),
+                        }
+                        content = content.trim_start().to_string();
+                    } else if skip_newline && content.starts_with('\n') {
+                        // skip the newline after non-use commands
+                        content.remove(0);
+                        span.start = Position::new(span.start.line + 1, 1);
+                    }
+                    if !content.is_empty() {
+                        let component = Component::Element(Element::Content(content), span);
+                        components.push(component);
+                    }
+                }
+                Token::Command(command) => {
+                    // `^|-u name-|` trims the whitespace on either side of a command
+                    let (markers, command) = lexer::trim_markers(&command);
+                    if markers.before {
+                        trim_previous(&mut components);
+                    }
+                    trim_next = markers.after;
+                    if !command.is_empty() {
+                        let parsed = commands_from_str(command).and_then(|commands| {
+                            block_components_from_commands(commands, directives, span)
+                        });
+                        match parsed {
+                            Ok(mut c) => components.append(&mut c),
+                            Err(reason) => {
+                                // mark the block as broken and keep parsing
+                                errors.push(Error::Parse {
+                                    location: location(span),
+                                    reason,
+                                });
+                                components.push(Component::Invalid);
+                            }
+                        }
+                    }
+                }
+                Token::Raw(mut content, markers) => {
+                    if markers.before {
+                        trim_previous(&mut components);
+                    }
+                    trim_next = markers.after;
+                    // like other commands, the raw command's own newline is skipped
+                    if content.starts_with('\n') {
+                        content.remove(0);
+                    }
+                    if !content.is_empty() {
+                        let component = Component::Element(Element::Content(content), span);
+                        components.push(component);
+                    }
+                    skip_newline = true;
+                    continue;
+                }
+                Token::Comment(markers) => {
+                    if markers.before {
+                        trim_previous(&mut components);
+                    }
+                    trim_next = markers.after;
+                    // comments leave nothing behind, not even their newline
+                    skip_newline = true;
+                    continue;
+                }
+                Token::Unclosed(reason) => {
+                    errors.push(Error::Parse {
+                        location: location(span),
+                        reason: reason.into(),
+                    });
+                    components.push(Component::Invalid);
+                }
             }
-            State::Command
-        }
-        fn close_command(buffer: &mut String, components: &mut Vec<Component>) -> State {
-            if !buffer.is_empty() {
-                let command = flush(buffer);
-                let commands = match commands_from_str(&command) {
-                    Ok(c) => c,
-                    Err(e) => panic!("{}", e),
-                };
-                match block_components_from_commands(commands) {
-                    Ok(mut c) => components.append(&mut c),
-                    Err(err) => return State::InvalidCommand(err),
+
+            // don't skip newline after commands that add to the block's
+            // content, like 'use' commands, or the end of a call
+            skip_newline = !matches!(
+                components.last(),
+                Some(Component::Element(..)) | Some(Component::CloseCall { .. })
+            );
+        }
+
+        let blocks = Block::build(components, path, indent, &mut errors, lints);
+        errors.sort_by_key(|e| e.location().map(|l| l.span.start));
+        match errors.is_empty() {
+            true => Ok(blocks),
+            false => Err(errors),
+        }
+    }
+    pub(crate) fn build(
+        components: Vec<Component>,
+        path: Option<&Path>,
+        mut indent: Indent,
+        errors: &mut Vec<Error>,
+        lints: &mut Vec<Error>,
+    ) -> Vec<Block> {
+        let location = |span| Location::new(path.map(Path::to_path_buf), span);
+        let mut blocks = Vec::new();
+        let mut components = components.into_iter().peekable();
+        loop {
+            let (name, span) = loop {
+                match components.next() {
+                    Some(Component::Open { name, span }) => break (name, span),
+                    None => return blocks, // iterator is empty
+                    Some(Component::Indent(unit, _)) => indent = unit,
+                    Some(Component::Element(Element::Content(content), span)) => {
+                        // whitespace between blocks is expected, anything else is dropped
+                        if let Some(start) = first_visible(&content, span.start) {
+                            lints.push(Error::StrayContent {
+                                location: location(Span::new(start, span.end)),
+                            });
+                        }
+                    }
+                    Some(Component::Attribute(_, span))
+                    | Some(Component::Element(_, span))
+                    | Some(Component::OpenCall(_, span))
+                    | Some(Component::CloseCall { span })
+                    | Some(Component::Close { span }) => lints.push(Error::StrayContent {
+                        location: location(span),
+                    }),
+                    Some(Component::Invalid) => continue,
+                }
+            };
+
+            // a block with any error is dropped once its errors are recorded
+            let mut valid = true;
+            let mut export = None;
+            let mut sets = Vec::new();
+            let mut params: Vec<Param> = Vec::new();
+            let mut elements = Vec::new();
+            // calls whose bodies are still being collected, innermost last
+            let mut calls: Vec<Element> = Vec::new();
+            let not_open = |c: &Component| !matches!(c, Component::Open { .. });
+            let mut closed = false;
+            while let Some(component) = components.next_if(not_open) {
+                match component {
+                    Component::Open { .. } => unreachable!(),
+                    Component::Attribute(attr, attr_span) => match attr {
+                        Attribute::Export(e) => match export {
+                            None => export = Some(e),
+                            Some(_) => {
+                                valid = false;
+                                errors.push(Error::Parse {
+                                    location: location(attr_span),
+                                    reason: "Multiple exports defined".into(),
+                                });
+                            }
+                        },
+                        // only trailing params can be left out, so only they have defaults
+                        Attribute::Param {
+                            name: v,
+                            default: None,
+                        } if params.iter().any(|p| p.default.is_some()) => {
+                            valid = false;
+                            errors.push(Error::Parse {
+                                location: location(attr_span),
+                                reason: format!(
+                                    "Param '{}' needs a default because it follows a param with one",
+                                    v
+                                ),
+                            });
+                        }
+                        Attribute::Param { name: v, default } => {
+                            match params.iter().any(|p| p.name == v) {
+                                false => params.push(Param {
+                                    name: v,
+                                    default,
+                                    span: attr_span,
+                                }),
+                                true => {
+                                    valid = false;
+                                    errors.push(Error::DuplicateParam {
+                                        block: name.clone(),
+                                        name: v,
+                                        location: location(attr_span),
+                                    });
+                                }
+                            }
+                        }
+                        Attribute::ArgumentSet(set) => sets.push(set),
+                    },
+                    Component::Element(e, _) => push_element(&mut calls, &mut elements, e),
+                    Component::OpenCall(e, _) => calls.push(e),
+                    Component::CloseCall { span } => match calls.pop() {
+                        Some(mut call) => {
+                            // remove the final newline and indentation before the end command
+                            if let Element::UseBlock {
+                                body: Some(body), ..
+                            } = &mut call
+                            {
+                                trim_final_line(body);
+                            }
+                            push_element(&mut calls, &mut elements, call);
+                        }
+                        None => {
+                            valid = false;
+                            errors.push(Error::Parse {
+                                location: location(span),
+                                reason: "Call end without a call".into(),
+                            });
+                        }
+                    },
+                    Component::Invalid => valid = false,
+                    Component::Indent(_, span) => {
+                        valid = false;
+                        errors.push(Error::Parse {
+                            location: location(span),
+                            reason: "Indentation can only be set outside of blocks".into(),
+                        });
+                    }
+                    Component::Close { .. } => {
+                        // remove the final newline before the close command
+                        trim_final_newline(&mut elements);
+                        closed = true;
+                        break;
+                    }
                 }
             }
 
-            // don't skip newline after 'use' commands
-            match components.last() {
-                Some(Component::Element(Element::UseBlock { .. })) => State::Content,
-                _ => State::SkipNewline,
+            if let Some(Element::UseBlock { span, .. }) = calls.first() {
+                valid = false;
+                errors.push(Error::Parse {
+                    location: location(*span),
+                    reason: "Call is never ended".into(),
+                });
             }
-        }
 
-        let mut line = 1;
-        let mut col = 0;
-        let mut state = State::Content;
-        let mut buffer = String::with_capacity(string.len());
-        let mut components = Vec::new();
-        for c in string.chars() {
-            if c == '\n' {
-                line += 1;
-                col = 0;
+            // argument sets add files to the block's file export
+            if let Some(set) = sets.first() {
+                match &mut export {
+                    Some(Export::File {
+                        sets: file_sets, ..
+                    }) => file_sets.append(&mut sets),
+                    _ => {
+                        valid = false;
+                        errors.push(Error::Parse {
+                            location: location(set.span),
+                            reason: "Argument sets require a file export".into(),
+                        });
+                    }
+                }
             }
-            col += 1;
-            state = match state {
-                State::Content => match c {
-                    COMMAND_FLAG => State::CommandFlag,
-                    _ => push_to_state(&mut buffer, c, State::Content),
-                },
-                State::CommandFlag => match c {
-                    COMMAND_START => close_content(&mut buffer, &mut components),
-                    COMMAND_FLAG => push_to_state(&mut buffer, c, State::CancelledFlag),
-                    _ => push_to_state(&mut buffer, c, State::Content),
-                },
-                State::Command => match c {
-                    COMMAND_END => close_command(&mut buffer, &mut components),
-                    _ => push_to_state(&mut buffer, c, State::Command),
-                },
-                State::SkipNewline => match c {
-                    '\n' => State::Content,
-                    _ => push_to_state(&mut buffer, c, State::Content),
-                },
-                State::CancelledFlag => match c {
-                    COMMAND_FLAG => push_to_state(&mut buffer, c, State::CancelledFlag),
-                    _ => push_to_state(&mut buffer, c, State::Content),
-                },
-                State::InvalidCommand(reason) => {
-                    return Err(format!("Invalid command ({}:{}): {}", line, col, reason));
+
+            // recover from an unclosed block by starting the next one
+            match (closed, components.peek()) {
+                (false, Some(Component::Open { name: nested, span })) => {
+                    valid = false;
+                    errors.push(Error::Parse {
+                        location: location(*span),
+                        reason: format!("Illegally nested block '{}' inside '{}'", nested, name),
+                    });
                 }
-            };
-        }
-        close_content(&mut buffer, &mut components);
+                (false, None) => lints.push(Error::UnclosedBlock {
+                    name: name.clone(),
+                    location: location(span),
+                }),
+                _ => {}
+            }
 
-        Block::build(components)
+            if valid {
+                let block = Block {
+                    name,
+                    location: location(span),
+                    export,
+                    params,
+                    elements,
+                    indent,
+                    native: None,
+                    source: None,
+                };
+                lints.append(&mut block.lint());
+                blocks.push(block);
+            }
+        }
     }
-    pub(crate) fn build(components: Vec<Component>) -> Result<Vec<Block>, String> {
-        let mut blocks = Vec::new();
-        let mut components = components.into_iter();
-        let name;
-        loop {
-            match components.next() {
-                Some(Component::Open { name: n }) => {
-                    name = n;
-                    break;
+    /// Why `flag` cannot name a custom command, if it can't.
+    pub(crate) fn reserved_flag(flag: &str) -> Option<&'static str> {
+        if flag.is_empty() || flag.contains(|c: char| c.is_whitespace() || c == COMMAND_END) {
+            Some("flags must be a single word")
+        } else if flag.starts_with(TRIM_MARKER) || flag.ends_with(TRIM_MARKER) {
+            Some("flags cannot start or end with a trim marker")
+        } else if BUILT_IN_COMMANDS.contains(&flag) || flag.starts_with(COMMENT_PREFIX) {
+            Some("it is a built-in command")
+        } else {
+            None
+        }
+    }
+    /// A block rendered by a Rust function instead of from source.
+    pub(crate) fn native(
+        name: &str,
+        params: &[&str],
+        native: Native,
+        indent: Indent,
+    ) -> Result<Block, Error> {
+        // native blocks have no source, so point at the start of nothing
+        let start = Position::new(1, 1);
+        let location = Location::new(None, Span::new(start, start));
+        if builtin::is_reserved(name) {
+            return Err(Error::Parse {
+                location,
+                reason: format!(
+                    "Block names starting with '{}' are reserved for built-in blocks",
+                    BUILTIN_PREFIX
+                ),
+            });
+        }
+        let mut declared: Vec<Param> = Vec::new();
+        for param in params {
+            if declared.iter().any(|p| p.name == *param) {
+                return Err(Error::DuplicateParam {
+                    block: name.to_string(),
+                    name: param.to_string(),
+                    location,
+                });
+            }
+            declared.push(Param {
+                name: param.to_string(),
+                default: None,
+                span: location.span,
+            });
+        }
+        Ok(Block {
+            name: name.to_string(),
+            location,
+            params: declared,
+            export: None,
+            elements: Vec::new(),
+            indent,
+            native: Some(native),
+            source: None,
+        })
+    }
+    /// Finds params that are declared but never used, or used but never declared.
+    fn lint(&self) -> Vec<Error> {
+        let location = |span: &Span| Location::new(self.location.path.clone(), *span);
+        let mut lints = Vec::new();
+        let mut referenced = Vec::new();
+        for element in uses(&self.elements) {
+            if let Element::UseBlock {
+                target,
+                arguments,
+                named,
+                span,
+                ..
+            } = element
+            {
+                let arguments = arguments.iter().flatten();
+                let named = named.iter().map(|(_, argument)| argument);
+                for argument in std::iter::once(target).chain(arguments).chain(named) {
+                    if let Argument::ParamName(name) = argument {
+                        if !self.params.iter().any(|p| p.name == *name) {
+                            lints.push(Error::MissingParam {
+                                block: self.name.clone(),
+                                name: name.clone(),
+                                location: Some(location(span)),
+                            });
+                        }
+                        referenced.push(name.as_str());
+                    }
                 }
-                None => return Ok(blocks), // iterator is empty
-                _ => continue,             // skip all possible components until we hit an open
             }
         }
-
-        let mut export = None;
-        let mut param_names = Vec::new();
-        let mut elements = Vec::new();
-        while let Some(component) = components.next() {
-            match component {
-                Component::Open { name } => {
-                    return Err(format!("Illegally nested block '{}'", name))
+        if let Some(Export::File { path, span, .. }) = &self.export {
+            for (_, name) in path_template(path) {
+                if !self.params.iter().any(|p| p.name == name) {
+                    lints.push(Error::MissingParam {
+                        block: self.name.clone(),
+                        name: name.to_string(),
+                        location: Some(location(span)),
+                    });
                 }
-                Component::Attribute(attr) => match attr {
-                    Attribute::Export(e) => match export {
-                        None => export = Some(e),
-                        Some(_) => return Err("Multiple exports defined".into()),
-                    },
-                    Attribute::ParamName(v) => match param_names.contains(&v) {
-                        false => param_names.push(v),
-                        true => return Err(format!("Duplicate value defined: {}", v)),
-                    },
-                },
-                Component::Element(e) => elements.push(e),
-                Component::Close => {
-                    // remove the final newline before the close command
-                    if let Some(Element::Content(last)) = elements.last_mut() {
-                        if last.ends_with('\n') {
-                            last.truncate(last.len() - 1);
+                referenced.push(name);
+            }
+        }
+        for param in &self.params {
+            if !referenced.contains(&param.name.as_str()) {
+                lints.push(Error::UnusedParam {
+                    block: self.name.clone(),
+                    name: param.name.clone(),
+                    location: location(&param.span),
+                });
+            }
+        }
+        lints
+    }
+    pub(crate) fn render(
+        &self,
+        context: &Context,
+        positional: Vec<Parameter>,
+        named: Vec<(String, Parameter)>,
+    ) -> Result<String, Error> {
+        let params = self.bind_params(positional, named, None)?;
+        self.render_with_params(context, params, 0, None, &mut Vec::new())
+    }
+    /// Checks that every named block this block uses exists and receives
+    /// the right number of arguments, and that every `#param` is declared.
+    pub(crate) fn check(&self, library: &HashMap<String, Block>) -> Vec<Error> {
+        let location = |span: &Span| Location::new(self.location.path.clone(), *span);
+        let mut errors: Vec<Error> = self
+            .lint()
+            .into_iter()
+            .filter(|e| matches!(e, Error::MissingParam { .. }))
+            .collect();
+        let mut targets: Vec<(&String, usize, Vec<&str>, Span)> = Vec::new();
+        for element in uses(&self.elements) {
+            if let Element::UseBlock {
+                target: Argument::Name(name),
+                arguments,
+                named,
+                body,
+                span,
+                ..
+            } = element
+            {
+                let positional = arguments.as_ref().map_or(0, Vec::len) + body.iter().count();
+                let named = named.iter().map(|(name, _)| name.as_str()).collect();
+                targets.push((name, positional, named, *span));
+            }
+        }
+        // a default that names a block is used without arguments
+        for param in &self.params {
+            if let Some(Argument::Name(name)) = &param.default {
+                targets.push((name, 0, Vec::new(), param.span));
+            }
+        }
+        for (name, positional, named, span) in targets {
+            if builtin::is_reserved(name) {
+                let received = positional + named.len();
+                match builtin::exists(name) {
+                    true if received > 0 => errors.push(Error::ArityMismatch {
+                        block: name.clone(),
+                        required: 0,
+                        expected: 0,
+                        received,
+                        location: Some(location(&span)),
+                    }),
+                    true => {}
+                    false => errors.push(Error::UnknownBlock {
+                        name: name.clone(),
+                        location: Some(location(&span)),
+                    }),
+                }
+                continue;
+            }
+            match library.get(name) {
+                Some(block) => {
+                    let checked = block.check_arguments(positional, &named, Some(location(&span)));
+                    if let Err(e) = checked {
+                        errors.push(e);
+                    }
+                }
+                None => errors.push(Error::UnknownBlock {
+                    name: name.clone(),
+                    location: Some(location(&span)),
+                }),
+            }
+        }
+        errors
+    }
+    /// Names of the blocks this block always uses, with the span of each use,
+    /// followed by the blocks its param defaults and file export pass as
+    /// arguments. Built-in blocks are left out.
+    pub(crate) fn dependencies(&self) -> Vec<(&str, Span)> {
+        let mut dependencies: Vec<(&str, Span)> = uses(&self.elements)
+            .into_iter()
+            .filter_map(|element| match element {
+                Element::UseBlock {
+                    target: Argument::Name(name),
+                    span,
+                    ..
+                } if !builtin::is_reserved(name) => Some((name.as_str(), *span)),
+                _ => None,
+            })
+            .collect();
+        for param in &self.params {
+            match &param.default {
+                Some(Argument::Name(name)) if !builtin::is_reserved(name) => {
+                    dependencies.push((name.as_str(), param.span))
+                }
+                _ => {}
+            }
+        }
+        if let Some(Export::File { sets, .. }) = &self.export {
+            for set in sets {
+                for argument in &set.arguments {
+                    match argument {
+                        Argument::Name(name) if !builtin::is_reserved(name) => {
+                            dependencies.push((name.as_str(), set.span))
                         }
+                        _ => {}
                     }
-                    break;
                 }
             }
         }
+        dependencies
+    }
+    /// The files this block's file export writes, one for each argument set.
+    pub(crate) fn file_exports(&self) -> Vec<Result<FileExport, Error>> {
+        let (path, sets, span) = match &self.export {
+            Some(Export::File { path, sets, span }) => (path, sets, span),
+            _ => return Vec::new(),
+        };
+        let location = |span: &Span| Location::new(self.location.path.clone(), *span);
+        let no_arguments = [ArgumentSet {
+            arguments: Vec::new(),
+            span: *span,
+        }];
+        let sets = match sets.is_empty() {
+            true => &no_arguments[..],
+            false => &sets[..],
+        };
+        sets.iter()
+            .map(|set| {
+                let parameters: Vec<Parameter> = set
+                    .arguments
+                    .iter()
+                    .filter_map(Argument::constant)
+                    .collect();
+                let bound =
+                    self.bind_params(parameters.clone(), Vec::new(), Some(location(&set.span)))?;
 
-        blocks.push(Block {
-            name,
-            export,
-            param_names,
-            elements,
-        });
-        blocks.append(&mut Block::build(components.collect())?);
+                // fill each `#param` in the path with its argument as written
+                let mut expanded = String::new();
+                let mut copied = 0;
+                for (range, name) in path_template(path) {
+                    let value = match bound.get(name) {
+                        Some(Parameter::Literal(value)) | Some(Parameter::Name(value)) => value,
+                        None => {
+                            return Err(Error::MissingParam {
+                                block: self.name.clone(),
+                                name: name.to_string(),
+                                location: Some(location(span)),
+                            })
+                        }
+                    };
+                    expanded.push_str(&path[copied..range.start]);
+                    expanded.push_str(value);
+                    copied = range.end;
+                }
+                expanded.push_str(&path[copied..]);
 
-        Ok(blocks)
+                Ok(FileExport {
+                    path: expanded,
+                    parameters,
+                    span: set.span,
+                })
+            })
+            .collect()
+    }
+    /// Matches positional arguments to params in order, then named
+    /// arguments to the params with their names, then defaults to the rest.
+    fn bind_params(
+        &self,
+        positional: Vec<Parameter>,
+        named: Vec<(String, Parameter)>,
+        location: Option<Location>,
+    ) -> Result<HashMap<String, Parameter>, Error> {
+        let names: Vec<&str> = named.iter().map(|(name, _)| name.as_str()).collect();
+        self.check_arguments(positional.len(), &names, location)?;
+        let names = self.params.iter().map(|p| p.name.clone());
+        let mut params = HashMap::from_iter(names.zip(positional));
+        params.extend(named);
+        for param in &self.params {
+            if params.contains_key(&param.name) {
+                continue;
+            }
+            if let Some(value) = param.default.as_ref().and_then(Argument::constant) {
+                params.insert(param.name.clone(), value);
+            }
+        }
+        Ok(params)
+    }
+    /// Checks that positional and named arguments can be bound to this
+    /// block's params, leaving out only params that have defaults.
+    pub(crate) fn check_arguments(
+        &self,
+        positional: usize,
+        named: &[&str],
+        location: Option<Location>,
+    ) -> Result<(), Error> {
+        let received = positional + named.len();
+        if positional > self.params.len() {
+            return Err(self.arity_mismatch(received, location));
+        }
+        let mut bound: Vec<&str> = self.params[..positional]
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        for name in named {
+            if !self.params.iter().any(|p| p.name == *name) {
+                return Err(Error::UnknownArgument {
+                    block: self.name.clone(),
+                    name: name.to_string(),
+                    location,
+                });
+            }
+            if bound.contains(name) {
+                return Err(Error::DuplicateArgument {
+                    block: self.name.clone(),
+                    name: name.to_string(),
+                    location,
+                });
+            }
+            bound.push(name);
+        }
+        let unbound = self
+            .params
+            .iter()
+            .any(|p| p.default.is_none() && !bound.contains(&p.name.as_str()));
+        match unbound {
+            true => Err(self.arity_mismatch(received, location)),
+            false => Ok(()),
+        }
     }
-    pub(crate) fn render(&self, library: &HashMap<String, Block>) -> Result<String, String> {
-        self.render_with_params(library, None, 0)
+    fn required_params(&self) -> usize {
+        self.params
+            .iter()
+            .rposition(|p| p.default.is_none())
+            .map_or(0, |i| i + 1)
+    }
+    fn arity_mismatch(&self, received: usize, location: Option<Location>) -> Error {
+        Error::ArityMismatch {
+            block: self.name.clone(),
+            required: self.required_params(),
+            expected: self.params.len(),
+            received,
+            location,
+        }
     }
     fn render_with_params(
         &self,
-        library: &HashMap<String, Block>,
-        params: Option<Vec<Parameter>>,
+        context: &Context,
+        params: HashMap<String, Parameter>,
         indentation: usize,
-    ) -> Result<String, String> {
-        fn build_params(
-            values: Vec<String>,
-            params: Vec<Parameter>,
-        ) -> Result<HashMap<String, Parameter>, String> {
-            match values.len() == params.len() {
-                true => {
-                    let zip = values.into_iter().zip(params.into_iter());
-                    let params = HashMap::from_iter(zip);
-                    Ok(params)
-                }
-                false => Err(format!(
-                    "Expected {} parameter(s), received {}",
-                    values.len(),
-                    params.len()
-                )),
+        location: Option<Location>,
+        stack: &mut Vec<String>,
+    ) -> Result<String, Error> {
+        stack.push(self.name.clone());
+        let buffer = match &self.native {
+            Some(native) => {
+                let render = self.render_native(native, context, &params, location, stack)?;
+                self.indent_lines(&render, indentation)
             }
+            None => self.render_elements(context, &self.elements, &params, indentation, stack)?,
+        };
+        stack.pop();
+
+        Ok(buffer)
+    }
+    /// Calls a native block with its arguments as text, rendering any
+    /// block passed as an argument first.
+    fn render_native(
+        &self,
+        native: &Native,
+        context: &Context,
+        params: &HashMap<String, Parameter>,
+        location: Option<Location>,
+        stack: &mut Vec<String>,
+    ) -> Result<String, Error> {
+        let mut arguments = Vec::new();
+        for param in &self.params {
+            let argument = match &params[&param.name] {
+                Parameter::Literal(literal) => literal.clone(),
+                Parameter::Name(name) => self.render_name(
+                    context,
+                    name,
+                    Arguments::default(),
+                    0,
+                    location.clone(),
+                    stack,
+                )?,
+            };
+            arguments.push(argument);
         }
-        fn evaluate(
-            arg: &Argument,
-            params: &HashMap<String, Parameter>,
-        ) -> Result<Parameter, String> {
+        native(&arguments).map_err(|source| Error::Native {
+            block: self.name.clone(),
+            source,
+            location,
+        })
+    }
+    /// Renders elements of this block, or of a call body inside it.
+    fn render_elements(
+        &self,
+        context: &Context,
+        elements: &[Element],
+        params: &HashMap<String, Parameter>,
+        indentation: usize,
+        stack: &mut Vec<String>,
+    ) -> Result<String, Error> {
+        let location = |span: &Span| Location::new(self.location.path.clone(), *span);
+        let evaluate = |arg: &Argument, span: &Span| -> Result<Parameter, Error> {
             match arg {
                 Argument::Literal(lit) => Ok(Parameter::Literal(lit.to_owned())),
                 Argument::Name(name) => Ok(Parameter::Name(name.to_owned())),
                 Argument::ParamName(name) => match params.get(name) {
                     Some(param) => Ok(param.clone()),
-                    None => Err(format!("Param named {} does not exist", name)),
+                    None => Err(Error::MissingParam {
+                        block: self.name.clone(),
+                        name: name.clone(),
+                        location: Some(location(span)),
+                    }),
                 },
             }
-        }
-
-        let params = match params {
-            Some(p) => build_params(self.param_names.clone(), p)?,
-            None => HashMap::new(),
         };
 
         let mut nested_indent = 0;
         let mut buffer = String::new();
-        for element in &self.elements {
+        for element in elements {
             let s = match element {
                 Element::Content(content) => {
                     // get indentation of current line
-                    let split: Vec<_> = content.split('\n').collect();
-                    if split.len() > 1 {
-                        nested_indent = 0;
-                        let mut line = *split.last().unwrap();
-                        while let Some(line_stripped) = line.strip_prefix('\t') {
-                            line = line_stripped;
-                            nested_indent += 1;
-                        }
+                    if let Some((_, line)) = content.rsplit_once('\n') {
+                        nested_indent = self.indent.levels(line);
                     }
 
                     // apply indentation to the content
-                    let content = match indentation {
-                        0 => content.clone(),
-                        _ => {
-                            let mut new_content = String::new();
-                            for c in content.chars() {
-                                match c {
-                                    '\n' => {
-                                        new_content.push('\n');
-                                        for _ in 0..indentation {
-                                            new_content.push('\t');
-                                        }
-                                    }
-                                    _ => new_content.push(c),
-                                }
-                            }
-                            new_content
-                        }
-                    };
-
-                    // return the content string
-                    content
+                    self.indent_lines(content, indentation)
                 }
                 Element::UseBlock {
                     indented,
                     target,
                     arguments,
+                    named,
+                    body,
+                    filters,
+                    span,
                 } => {
-                    let target_param = evaluate(target, &params)?;
-                    match target_param {
-                        Parameter::Literal(literal) => literal,
+                    let target_param = evaluate(target, span)?;
+                    let indentation = match indented {
+                        true => indentation + nested_indent,
+                        false => 0,
+                    };
+                    let output = match target_param {
+                        // literals are indented like the blocks they stand in for
+                        Parameter::Literal(literal) => self.indent_lines(&literal, indentation),
                         Parameter::Name(name) => {
-                            let block = match library.get(&name) {
-                                Some(b) => b,
-                                None => return Err(format!("Using unregistered block '{}'", name)),
-                            };
-                            let parameters: Option<Vec<Parameter>> = match arguments {
-                                Some(p) => {
-                                    let evaluated_params: Result<Vec<Parameter>, String> =
-                                        p.iter().map(|p| evaluate(p, &params)).collect();
-                                    Some(evaluated_params?)
-                                }
-                                None => None,
-                            };
-                            let indentation = match indented {
-                                true => indentation + nested_indent,
-                                false => 0,
+                            let mut positional: Vec<Parameter> = match arguments {
+                                Some(p) => p
+                                    .iter()
+                                    .map(|p| evaluate(p, span))
+                                    .collect::<Result<_, _>>()?,
+                                None => Vec::new(),
                             };
-
-                            block.render_with_params(library, parameters, indentation)?
+                            // a call body is rendered here, where its params are bound,
+                            // without the indentation of the call it is written under
+                            if let Some(body) = body {
+                                let body = self.render_elements(context, body, params, 0, stack)?;
+                                let body = self.dedent_lines(&body, nested_indent);
+                                positional.push(Parameter::Literal(body));
+                            }
+                            let named = named
+                                .iter()
+                                .map(|(name, arg)| Ok((name.clone(), evaluate(arg, span)?)))
+                                .collect::<Result<_, Error>>()?;
+                            self.render_name(
+                                context,
+                                &name,
+                                Arguments { positional, named },
+                                indentation,
+                                Some(location(span)),
+                                stack,
+                            )?
                         }
-                    }
+                    };
+                    filters
+                        .iter()
+                        .fold(output, |output, filter| filter.apply(&output))
                 }
             };
             buffer.push_str(&s);
         }
-
         Ok(buffer)
     }
+    /// Renders the block or built-in block named by a use or a param of this
+    /// block, if it exists, accepts the arguments and isn't already being
+    /// rendered.
+    fn render_name(
+        &self,
+        context: &Context,
+        name: &str,
+        arguments: Arguments,
+        indentation: usize,
+        location: Option<Location>,
+        stack: &mut Vec<String>,
+    ) -> Result<String, Error> {
+        if builtin::is_reserved(name) {
+            let received = arguments.positional.len() + arguments.named.len();
+            return self.render_builtin(name, received, context, location);
+        }
+        if let Some(start) = stack.iter().position(|n| n == name) {
+            let mut chain = stack[start..].to_vec();
+            chain.push(name.to_string());
+            return Err(Error::Cycle { chain, location });
+        }
+        let block = match context.blocks.get(name) {
+            Some(b) => b,
+            None => {
+                return Err(Error::UnknownBlock {
+                    name: name.to_string(),
+                    location,
+                })
+            }
+        };
+        let params = block.bind_params(arguments.positional, arguments.named, location.clone())?;
+        block.render_with_params(context, params, indentation, location, stack)
+    }
+    /// Renders a built-in block used from this block, which takes no arguments.
+    fn render_builtin(
+        &self,
+        name: &str,
+        received: usize,
+        context: &Context,
+        location: Option<Location>,
+    ) -> Result<String, Error> {
+        match builtin::render(name, self, context) {
+            None => Err(Error::UnknownBlock {
+                name: name.to_string(),
+                location,
+            }),
+            Some(_) if received > 0 => Err(Error::ArityMismatch {
+                block: name.to_string(),
+                required: 0,
+                expected: 0,
+                received,
+                location,
+            }),
+            Some(render) => Ok(render),
+        }
+    }
+    /// Removes up to `levels` of this block's indentation from the start of
+    /// every line of `text`.
+    fn dedent_lines(&self, text: &str, levels: usize) -> String {
+        if levels == 0 {
+            return text.to_string();
+        }
+        let unit = self.indent.unit();
+        let lines: Vec<&str> = text
+            .split('\n')
+            .map(|mut line| {
+                for _ in 0..levels {
+                    line = match line.strip_prefix('\t') {
+                        Some(rest) => rest,
+                        None => match line.strip_prefix(unit.as_str()) {
+                            Some(rest) => rest,
+                            None => break,
+                        },
+                    };
+                }
+                line
+            })
+            .collect();
+        lines.join("\n")
+    }
+    /// Indents every line of `text` after the first by `levels` of this
+    /// block's indentation.
+    fn indent_lines(&self, text: &str, levels: usize) -> String {
+        match levels {
+            0 => text.to_string(),
+            _ => text.replace('\n', &format!("\n{}", self.indent.unit().repeat(levels))),
+        }
+    }
+}
+
+impl Argument {
+    /// The value of an argument that does not depend on any params.
+    fn constant(&self) -> Option<Parameter> {
+        match self {
+            Argument::Literal(literal) => Some(Parameter::Literal(literal.clone())),
+            Argument::Name(name) => Some(Parameter::Name(name.clone())),
+            Argument::ParamName(_) => None,
+        }
+    }
+}
+
+/// Adds an element to the innermost open call, or to the block itself.
+fn push_element(calls: &mut [Element], elements: &mut Vec<Element>, element: Element) {
+    match calls.last_mut() {
+        Some(Element::UseBlock {
+            body: Some(body), ..
+        }) => body.push(element),
+        _ => elements.push(element),
+    }
+}
+
+fn trim_final_newline(elements: &mut [Element]) {
+    if let Some(Element::Content(last)) = elements.last_mut() {
+        if last.ends_with('\n') {
+            last.truncate(last.len() - 1);
+        }
+    }
+}
+
+/// Removes the whitespace at the end of the content before a trimmed command.
+fn trim_previous(components: &mut Vec<Component>) {
+    if let Some(Component::Element(Element::Content(content), _)) = components.last_mut() {
+        content.truncate(content.trim_end().len());
+        if content.is_empty() {
+            components.pop();
+        }
+    }
+}
+
+/// Removes the whitespace that indents the last line of the elements, along
+/// with the newline before it, if nothing else is on that line.
+fn trim_final_line(elements: &mut Vec<Element>) {
+    let only = elements.len() == 1;
+    if let Some(Element::Content(last)) = elements.last_mut() {
+        let line = last.trim_end_matches([' ', '\t']);
+        if line.ends_with('\n') {
+            last.truncate(line.len() - 1);
+        } else if line.is_empty() && only {
+            // the body starts at the beginning of the line
+            elements.clear();
+        }
+    }
+}
+
+/// Every use in a list of elements, including the uses inside call bodies.
+fn uses(elements: &[Element]) -> Vec<&Element> {
+    let mut uses = Vec::new();
+    for element in elements {
+        if let Element::UseBlock { body, .. } = element {
+            uses.push(element);
+            uses.extend(body.iter().flat_map(|body| self::uses(body)));
+        }
+    }
+    uses
+}
+
+/// Every `#param` in an export path, with the byte range it covers. Names
+/// can't end with `-`.
+fn path_template(path: &str) -> Vec<(Range<usize>, &str)> {
+    let is_name = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+    let mut params = Vec::new();
+    let mut chars = path.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c != '#' {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some((i, c)) = chars.next_if(|(_, c)| is_name(*c)) {
+            end = i + c.len_utf8();
+        }
+        // a trailing `-` separates the name from the text after it, as in
+        // `#slug-#lang.html`
+        end = start + path[start..end].trim_end_matches('-').len();
+        if end > start + 1 {
+            params.push((start..end, &path[start + 1..end]));
+        }
+    }
+    params
+}
+
+/// Calls `report` with the leading whitespace of every line in `text` that
+/// mixes tabs and spaces.
+fn mixed_indentation(text: &str, start: Position, mut report: impl FnMut(Span)) {
+    for (i, text) in text.split('\n').enumerate() {
+        let line = start.line + i;
+        // only whole lines are measured
+        if i > 0 || start.col == 1 {
+            let indent: String = text
+                .chars()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .collect();
+            if indent.contains(' ') && indent.contains('\t') {
+                let end = Position::new(line, indent.chars().count());
+                report(Span::new(Position::new(line, 1), end));
+            }
+        }
+    }
+}
+
+/// The position of the first non-whitespace character in `text`, if any.
+fn first_visible(text: &str, start: Position) -> Option<Position> {
+    let mut position = start;
+    for c in text.chars() {
+        match c {
+            '\n' => position = Position::new(position.line + 1, 1),
+            c if c.is_whitespace() => position.col += 1,
+            _ => return Some(position),
+        }
+    }
+    None
 }
@@ -0,0 +1,99 @@
+use crate::block::{Block, Context};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Starts the name of every built-in block. Blocks in a library can't use it.
+pub(crate) const BUILTIN_PREFIX: char = '@';
+const BLOCK: &str = "@block";
+const FILE: &str = "@file";
+const EXPORT: &str = "@export";
+const DATE: &str = "@date";
+const TIMESTAMP: &str = "@timestamp";
+const VERSION: &str = "@version";
+const BUILTINS: [&str; 6] = [BLOCK, FILE, EXPORT, DATE, TIMESTAMP, VERSION];
+
+/// Set by reproducible builds to the time that outputs should claim.
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Whether `name` is in the namespace reserved for built-in blocks.
+pub(crate) fn is_reserved(name: &str) -> bool {
+    name.starts_with(BUILTIN_PREFIX)
+}
+
+/// Whether `name` is a built-in block.
+pub(crate) fn exists(name: &str) -> bool {
+    BUILTINS.contains(&name)
+}
+
+/// Renders a built-in block used from `block`. Context that doesn't apply,
+/// like the export path outside of a file export, renders as nothing.
+pub(crate) fn render(name: &str, block: &Block, context: &Context) -> Option<String> {
+    let render = match name {
+        BLOCK => block.name.clone(),
+        // the same on every machine, whatever directory the build runs in
+        FILE => match &block.source {
+            Some(path) => {
+                let components: Vec<_> = path
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect();
+                components.join("/")
+            }
+            None => String::new(),
+        },
+        EXPORT => context.export.unwrap_or_default().to_string(),
+        DATE => {
+            let (year, month, day) = civil_date(context.time / 86400);
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        TIMESTAMP => {
+            let (year, month, day) = civil_date(context.time / 86400);
+            let seconds = context.time % 86400;
+            format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                year,
+                month,
+                day,
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )
+        }
+        VERSION => env!("CARGO_PKG_VERSION").to_string(),
+        _ => return None,
+    };
+    Some(render)
+}
+
+/// The time built-in blocks render, in seconds since the Unix epoch:
+/// `SOURCE_DATE_EPOCH` if it is set, or else the current time.
+pub(crate) fn build_time() -> u64 {
+    let epoch = std::env::var(SOURCE_DATE_EPOCH)
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok());
+    match epoch {
+        Some(epoch) => epoch,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+    }
+}
+
+/// The year, month and day of a count of days since 1970-01-01, in the
+/// proleptic Gregorian calendar.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // shift the epoch to 0000-03-01, so leap days end each 400 year era
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
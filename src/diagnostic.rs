@@ -0,0 +1,153 @@
+use crate::error::{Error, Location};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const TAB_WIDTH: usize = 4;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const GREEN: &str = "\x1b[1;32m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Renders errors as rustc-style diagnostics, quoting the offending source.
+pub struct Renderer {
+    color: bool,
+    sources: HashMap<PathBuf, Option<Vec<String>>>,
+}
+
+impl Renderer {
+    pub fn new(color: bool) -> Renderer {
+        Renderer {
+            color,
+            sources: HashMap::new(),
+        }
+    }
+
+    pub fn render(&mut self, error: &Error) -> String {
+        let errors: Vec<String> = error
+            .errors()
+            .into_iter()
+            .map(|e| self.render_one(e, "error", RED))
+            .collect();
+        errors.join("\n")
+    }
+
+    pub fn render_warning(&mut self, warning: &Error) -> String {
+        self.render_one(warning, "warning", YELLOW)
+    }
+
+    fn render_one(&mut self, error: &Error, label: &str, color: &str) -> String {
+        let root = error.root();
+        let mut output = String::new();
+        output.push_str(&self.heading(label, color, &root.message()));
+        if let Some(location) = root.location() {
+            output.push_str(&self.snippet(location, color));
+        }
+        // io and native errors carry their cause as a source
+        if let Some(source) = std::error::Error::source(root) {
+            output.push_str(&self.inline_note(&source.to_string()));
+        }
+        for note in error.notes() {
+            match note.location {
+                Some(location) => {
+                    output.push_str(&self.heading("note", GREEN, &note.message));
+                    output.push_str(&self.snippet(&location, GREEN));
+                }
+                None => output.push_str(&self.inline_note(&note.message)),
+            }
+        }
+        output
+    }
+
+    fn heading(&self, label: &str, color: &str, message: &str) -> String {
+        match self.color {
+            true => format!(
+                "{}{}{}: {}{}{}\n",
+                color, label, RESET, BOLD, message, RESET
+            ),
+            false => format!("{}: {}\n", label, message),
+        }
+    }
+
+    fn inline_note(&self, message: &str) -> String {
+        format!("{} {}\n", self.paint(BLUE, "  = note:"), message)
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{}{}{}", color, text, RESET),
+            false => text.to_string(),
+        }
+    }
+
+    fn snippet(&mut self, location: &Location, color: &str) -> String {
+        let span = location.span;
+        let line_number = span.start.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        let mut output = format!("{}{} {}\n", gutter, self.paint(BLUE, "-->"), location);
+        let line = match &location.path {
+            Some(path) => self.line(path, span.start.line),
+            None => None,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => return output,
+        };
+
+        // expand tabs so the underline lines up with the quoted source
+        let mut text = String::new();
+        let mut start = 0;
+        let mut end = 0;
+        for (i, c) in line.chars().enumerate() {
+            let col = i + 1;
+            if col == span.start.col {
+                start = text.chars().count();
+            }
+            match c {
+                '\t' => text.push_str(&" ".repeat(TAB_WIDTH)),
+                c => text.push(c),
+            }
+            if span.end.line == span.start.line && col == span.end.col {
+                end = text.chars().count();
+            }
+        }
+        if span.end.line != span.start.line || end <= start {
+            end = text.chars().count().max(start + 1);
+        }
+
+        let bar = self.paint(BLUE, "|");
+        output.push_str(&format!("{} {}\n", gutter, bar));
+        output.push_str(&format!(
+            "{} {} {}\n",
+            self.paint(BLUE, &line_number),
+            bar,
+            text
+        ));
+        output.push_str(&format!(
+            "{} {} {}{}\n",
+            gutter,
+            bar,
+            " ".repeat(start),
+            self.paint(color, &"^".repeat(end - start))
+        ));
+        output
+    }
+
+    fn line(&mut self, path: &Path, line: usize) -> Option<String> {
+        let lines = self.sources.entry(path.to_path_buf()).or_insert_with(|| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|source| source.lines().map(String::from).collect())
+        });
+        match lines {
+            Some(lines) => lines.get(line - 1).cloned(),
+            None => None,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{
+    block::{self, Attribute, Component, Element},
+    error::Span,
+};
+
+/// A custom command, registered on a library under its own flag.
+pub trait Directive: Send + Sync {
+    /// Turns the arguments of a `^|flag ...|` command into what it adds to
+    /// the block it appears in. An error is reported as a parse error at
+    /// the command.
+    fn expand(
+        &self,
+        arguments: &[Argument],
+        named: &[(String, Argument)],
+    ) -> Result<Vec<Contribution>, String>;
+}
+
+/// An argument to a custom command, as written after its flag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Argument {
+    /// `(text)`, inserted as written.
+    Literal(String),
+    /// A bare word, naming a block in the library.
+    Block(String),
+    /// `#name`, one of the params of the block the command is in.
+    Param(String),
+}
+
+/// Something a custom command adds to the block it appears in.
+#[derive(Clone, Debug)]
+pub enum Contribution {
+    /// Text, as if it had been written in place of the command.
+    Content(String),
+    /// A use of a block or param, like `^|u target arguments|`.
+    Use {
+        indented: bool,
+        target: Argument,
+        arguments: Vec<Argument>,
+        named: Vec<(String, Argument)>,
+    },
+    /// A param declaration, like `^|p name|`.
+    Param {
+        name: String,
+        default: Option<Argument>,
+    },
+}
+
+/// Expands a custom command into the components it contributes.
+pub(crate) fn components(
+    directive: &dyn Directive,
+    commands: impl Iterator<Item = block::Command>,
+    span: Span,
+) -> Result<Vec<Component>, String> {
+    let mut arguments = Vec::new();
+    let mut named = Vec::new();
+    for next in commands {
+        match next {
+            block::Command::Argument(arg) => arguments.push(Argument::from(arg)),
+            block::Command::Keyword(name, arg) => named.push((name, Argument::from(arg))),
+            block::Command::Pipe => return Err("Custom commands cannot be filtered".into()),
+            block::Command::Flag(_) => {}
+        }
+    }
+
+    let mut components = Vec::new();
+    for contribution in directive.expand(&arguments, &named)? {
+        let component = match contribution {
+            Contribution::Content(content) => Component::Element(Element::Content(content), span),
+            Contribution::Use {
+                indented,
+                target,
+                arguments,
+                named,
+            } => {
+                let arguments: Vec<block::Argument> =
+                    arguments.into_iter().map(block::Argument::from).collect();
+                let element = Element::UseBlock {
+                    indented,
+                    target: target.into(),
+                    arguments: (!arguments.is_empty()).then_some(arguments),
+                    named: named
+                        .into_iter()
+                        .map(|(name, arg)| (name, arg.into()))
+                        .collect(),
+                    body: None,
+                    filters: Vec::new(),
+                    span,
+                };
+                Component::Element(element, span)
+            }
+            Contribution::Param {
+                default: Some(Argument::Param(_)),
+                ..
+            } => return Err("Param defaults must be literals or block names".into()),
+            Contribution::Param { name, default } => {
+                let attribute = Attribute::Param {
+                    name,
+                    default: default.map(block::Argument::from),
+                };
+                Component::Attribute(attribute, span)
+            }
+        };
+        components.push(component);
+    }
+    Ok(components)
+}
+
+impl From<block::Argument> for Argument {
+    fn from(argument: block::Argument) -> Argument {
+        match argument {
+            block::Argument::Literal(literal) => Argument::Literal(literal),
+            block::Argument::Name(name) => Argument::Block(name),
+            block::Argument::ParamName(name) => Argument::Param(name),
+        }
+    }
+}
+
+impl From<Argument> for block::Argument {
+    fn from(argument: Argument) -> block::Argument {
+        match argument {
+            Argument::Literal(literal) => block::Argument::Literal(literal),
+            Argument::Block(name) => block::Argument::Name(name),
+            Argument::Param(name) => block::Argument::ParamName(name),
+        }
+    }
+}
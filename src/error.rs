@@ -0,0 +1,365 @@
+use crate::library::Generation;
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+};
+
+/// A line and column in a source file, both starting at 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// An inclusive range of characters in a source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// A span within a source file, if the source came from a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub path: Option<PathBuf>,
+    pub span: Span,
+}
+
+/// Supplementary information attached to an error.
+#[derive(Clone, Debug)]
+pub struct Note {
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Source text could not be parsed into blocks.
+    Parse { location: Location, reason: String },
+    /// Two blocks in a library were given the same name.
+    DuplicateBlock {
+        name: String,
+        location: Location,
+        first: Box<Location>,
+    },
+    /// A block declared the same parameter twice.
+    DuplicateParam {
+        block: String,
+        name: String,
+        location: Location,
+    },
+    /// A block was used that does not exist in the library.
+    UnknownBlock {
+        name: String,
+        location: Option<Location>,
+    },
+    /// A block was used with the wrong number of arguments.
+    ArityMismatch {
+        block: String,
+        /// The params that have no default and must always be given.
+        required: usize,
+        expected: usize,
+        received: usize,
+        location: Option<Location>,
+    },
+    /// A named argument was given for a param the block does not declare.
+    UnknownArgument {
+        block: String,
+        name: String,
+        location: Option<Location>,
+    },
+    /// A param was given more than one argument.
+    DuplicateArgument {
+        block: String,
+        name: String,
+        location: Option<Location>,
+    },
+    /// A `#param` was referenced that the block does not declare.
+    MissingParam {
+        block: String,
+        name: String,
+        location: Option<Location>,
+    },
+    /// Text or commands outside of any block, which are ignored.
+    StrayContent { location: Location },
+    /// A block that reaches the end of its file without an end command.
+    UnclosedBlock { name: String, location: Location },
+    /// A line is indented with both tabs and spaces.
+    MixedIndentation { location: Location },
+    /// A block declares a parameter it never uses.
+    UnusedParam {
+        block: String,
+        name: String,
+        location: Location,
+    },
+    /// A file export path that cannot be written.
+    InvalidExport {
+        path: String,
+        reason: String,
+        location: Location,
+    },
+    /// A custom command was registered under a flag that cannot be used.
+    InvalidDirective { flag: String, reason: String },
+    /// A block includes itself, directly or through other blocks.
+    Cycle {
+        chain: Vec<String>,
+        location: Option<Location>,
+    },
+    /// A file could not be read or written.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A native block registered from Rust returned an error.
+    Native {
+        block: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+        location: Option<Location>,
+    },
+    /// Rendering a block failed.
+    Render { block: String, source: Box<Error> },
+    /// Importing the blocks generated by a block export failed.
+    Generated {
+        block: String,
+        pass: usize,
+        source: Box<Error>,
+    },
+    /// Block exports kept generating new block exports.
+    GenerationLimit {
+        limit: usize,
+        passes: Vec<Vec<Generation>>,
+    },
+    /// Several independent errors, such as every parse error in a directory.
+    Multiple(Vec<Error>),
+}
+
+impl Position {
+    pub(crate) fn new(line: usize, col: usize) -> Position {
+        Position { line, col }
+    }
+}
+
+impl Span {
+    pub(crate) fn new(start: Position, end: Position) -> Span {
+        Span { start, end }
+    }
+}
+
+impl Location {
+    pub(crate) fn new(path: Option<PathBuf>, span: Span) -> Location {
+        Location { path, span }
+    }
+}
+
+impl Error {
+    /// The source location most closely associated with this error.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::Parse { location, .. }
+            | Error::DuplicateBlock { location, .. }
+            | Error::DuplicateParam { location, .. }
+            | Error::StrayContent { location }
+            | Error::UnclosedBlock { location, .. }
+            | Error::MixedIndentation { location }
+            | Error::UnusedParam { location, .. }
+            | Error::InvalidExport { location, .. } => Some(location),
+            Error::UnknownBlock { location, .. }
+            | Error::ArityMismatch { location, .. }
+            | Error::UnknownArgument { location, .. }
+            | Error::DuplicateArgument { location, .. }
+            | Error::MissingParam { location, .. }
+            | Error::Cycle { location, .. }
+            | Error::Native { location, .. } => location.as_ref(),
+            Error::InvalidDirective { .. } | Error::Io { .. } | Error::Multiple(_) => None,
+            Error::Render { source, .. } | Error::Generated { source, .. } => source.location(),
+            Error::GenerationLimit { .. } => None,
+        }
+    }
+}
+
+impl Error {
+    /// A description of this error without its location.
+    pub fn message(&self) -> String {
+        match self {
+            Error::Parse { reason, .. } => reason.clone(),
+            Error::DuplicateBlock { name, .. } => format!("duplicate block name '{}'", name),
+            Error::DuplicateParam { block, name, .. } => {
+                format!("parameter '{}' defined twice in block '{}'", name, block)
+            }
+            Error::UnknownBlock { name, .. } => format!("unknown block '{}'", name),
+            Error::ArityMismatch {
+                block,
+                required,
+                expected,
+                received,
+                ..
+            } => match required == expected {
+                true => format!(
+                    "block '{}' expects {} parameter(s), received {}",
+                    block, expected, received
+                ),
+                false => format!(
+                    "block '{}' expects {} to {} parameter(s), received {}",
+                    block, required, expected, received
+                ),
+            },
+            Error::UnknownArgument { block, name, .. } => {
+                format!("block '{}' has no param '{}'", block, name)
+            }
+            Error::DuplicateArgument { block, name, .. } => format!(
+                "param '{}' of block '{}' received more than one argument",
+                name, block
+            ),
+            Error::MissingParam { block, name, .. } => {
+                format!("param '{}' does not exist in block '{}'", name, block)
+            }
+            Error::StrayContent { .. } => "content outside of a block is ignored".into(),
+            Error::UnclosedBlock { name, .. } => format!("block '{}' is never closed", name),
+            Error::MixedIndentation { .. } => "line is indented with both tabs and spaces".into(),
+            Error::UnusedParam { block, name, .. } => {
+                format!("param '{}' is never used in block '{}'", name, block)
+            }
+            Error::InvalidExport { path, reason, .. } => {
+                format!("cannot export to '{}': {}", path, reason)
+            }
+            Error::InvalidDirective { flag, reason } => {
+                format!("cannot register command '{}': {}", flag, reason)
+            }
+            Error::Cycle { chain, .. } => format!("circular include: {}", chain.join(" -> ")),
+            Error::Io { path, .. } => format!("could not access '{}'", path.to_string_lossy()),
+            Error::Native { block, .. } => format!("native block '{}' failed", block),
+            Error::Render { block, .. } => format!("failed to render block '{}'", block),
+            Error::Generated { block, pass, .. } => format!(
+                "failed to import blocks generated by '{}' in pass {}",
+                block, pass
+            ),
+            Error::GenerationLimit { limit, .. } => format!(
+                "block exports were still generating new block exports after {} pass(es)",
+                limit
+            ),
+            Error::Multiple(errors) => format!("{} errors", errors.len()),
+        }
+    }
+
+    /// Every individual error, flattening any nested `Error::Multiple`.
+    pub fn errors(&self) -> Vec<&Error> {
+        match self {
+            Error::Multiple(errors) => errors.iter().flat_map(Error::errors).collect(),
+            _ => vec![self],
+        }
+    }
+
+    /// Supplementary information pointing at related source locations.
+    pub fn notes(&self) -> Vec<Note> {
+        match self {
+            Error::DuplicateBlock { name, first, .. } => vec![Note {
+                message: format!("block '{}' first defined here", name),
+                location: Some(first.as_ref().clone()),
+            }],
+            Error::Render { block, source } => {
+                let mut notes = source.notes();
+                notes.push(Note {
+                    message: format!("while rendering block '{}'", block),
+                    location: None,
+                });
+                notes
+            }
+            Error::Generated {
+                block,
+                pass,
+                source,
+            } => {
+                let mut notes = source.notes();
+                notes.push(Note {
+                    message: format!("in blocks generated by '{}' during pass {}", block, pass),
+                    location: None,
+                });
+                notes
+            }
+            Error::GenerationLimit { passes, .. } => passes
+                .iter()
+                .enumerate()
+                .map(|(i, generations)| {
+                    let generations: Vec<String> = generations
+                        .iter()
+                        .map(|g| format!("'{}' generated [{}]", g.block, g.generated.join(", ")))
+                        .collect();
+                    Note {
+                        message: format!("pass {}: {}", i + 1, generations.join("; ")),
+                        location: None,
+                    }
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub(crate) fn into_errors(self) -> Vec<Error> {
+        match self {
+            Error::Multiple(errors) => errors.into_iter().flat_map(Error::into_errors).collect(),
+            _ => vec![self],
+        }
+    }
+
+    /// The innermost error, skipping over any rendering context.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Render { source, .. } | Error::Generated { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(
+                f,
+                "{}:{}:{}",
+                path.to_string_lossy(),
+                self.span.start.line,
+                self.span.start.col
+            ),
+            None => write!(f, "{}:{}", self.span.start.line, self.span.start.col),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { .. }
+            | Error::Render { .. }
+            | Error::Generated { .. }
+            | Error::GenerationLimit { .. } => write!(f, "{}", self.message()),
+            Error::Multiple(errors) => {
+                let lines: Vec<String> = errors.iter().map(Error::to_string).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            _ => match self.location() {
+                Some(location) => write!(f, "{}: {}", location, self.message()),
+                None => write!(f, "{}", self.message()),
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Native { source, .. } => Some(source.as_ref()),
+            Error::Render { source, .. } | Error::Generated { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Vec<Error>> for Error {
+    fn from(mut errors: Vec<Error>) -> Error {
+        match errors.len() {
+            1 => errors.remove(0),
+            _ => Error::Multiple(errors),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::block::Argument;
+
+const ESCAPE_HTML: &str = "escape_html";
+const ESCAPE_JSON: &str = "escape_json";
+const ESCAPE_SHELL: &str = "escape_shell";
+const URL_ENCODE: &str = "url_encode";
+const UPPER: &str = "upper";
+const LOWER: &str = "lower";
+const TRIM: &str = "trim";
+const SLUGIFY: &str = "slugify";
+const REPLACE: &str = "replace";
+
+/// A transformation applied to the output of a use command, as in
+/// `^|u title |> escape_html|`.
+#[derive(Clone)]
+pub(crate) enum Filter {
+    EscapeHtml,
+    EscapeJson,
+    EscapeShell,
+    UrlEncode,
+    Upper,
+    Lower,
+    Trim,
+    Slugify,
+    Replace { from: String, to: String },
+}
+
+impl Filter {
+    pub(crate) fn parse(name: &str, arguments: Vec<Argument>) -> Result<Filter, String> {
+        let filter = match name {
+            ESCAPE_HTML => Filter::EscapeHtml,
+            ESCAPE_JSON => Filter::EscapeJson,
+            ESCAPE_SHELL => Filter::EscapeShell,
+            URL_ENCODE => Filter::UrlEncode,
+            UPPER => Filter::Upper,
+            LOWER => Filter::Lower,
+            TRIM => Filter::Trim,
+            SLUGIFY => Filter::Slugify,
+            REPLACE => {
+                return match <[Argument; 2]>::try_from(arguments) {
+                    Ok([Argument::Literal(from), Argument::Literal(to)]) => {
+                        Ok(Filter::Replace { from, to })
+                    }
+                    _ => Err("Filter 'replace' expects two literals".into()),
+                }
+            }
+            _ => return Err(format!("Unknown filter '{}'", name)),
+        };
+        match arguments.is_empty() {
+            true => Ok(filter),
+            false => Err(format!("Filter '{}' takes no arguments", name)),
+        }
+    }
+
+    pub(crate) fn apply(&self, text: &str) -> String {
+        match self {
+            Filter::EscapeHtml => {
+                let mut escaped = String::with_capacity(text.len());
+                for c in text.chars() {
+                    match c {
+                        '&' => escaped.push_str("&amp;"),
+                        '<' => escaped.push_str("&lt;"),
+                        '>' => escaped.push_str("&gt;"),
+                        '"' => escaped.push_str("&quot;"),
+                        '\'' => escaped.push_str("&#39;"),
+                        c => escaped.push(c),
+                    }
+                }
+                escaped
+            }
+            Filter::EscapeJson => {
+                let mut escaped = String::with_capacity(text.len());
+                for c in text.chars() {
+                    match c {
+                        '"' => escaped.push_str("\\\""),
+                        '\\' => escaped.push_str("\\\\"),
+                        '\n' => escaped.push_str("\\n"),
+                        '\r' => escaped.push_str("\\r"),
+                        '\t' => escaped.push_str("\\t"),
+                        c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => escaped.push(c),
+                    }
+                }
+                escaped
+            }
+            Filter::EscapeShell => format!("'{}'", text.replace('\'', "'\\''")),
+            Filter::UrlEncode => {
+                let mut encoded = String::with_capacity(text.len());
+                for byte in text.bytes() {
+                    match byte {
+                        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                            encoded.push(byte as char)
+                        }
+                        _ => encoded.push_str(&format!("%{:02X}", byte)),
+                    }
+                }
+                encoded
+            }
+            Filter::Upper => text.to_uppercase(),
+            Filter::Lower => text.to_lowercase(),
+            Filter::Trim => text.trim().to_string(),
+            Filter::Slugify => {
+                let mut slug = String::with_capacity(text.len());
+                for c in text.chars().flat_map(char::to_lowercase) {
+                    match c.is_alphanumeric() {
+                        true => slug.push(c),
+                        // runs of anything else become a single dash
+                        false if !slug.is_empty() && !slug.ends_with('-') => slug.push('-'),
+                        false => {}
+                    }
+                }
+                slug.trim_end_matches('-').to_string()
+            }
+            Filter::Replace { from, to } => match from.is_empty() {
+                true => text.to_string(),
+                false => text.replace(from.as_str(), to),
+            },
+        }
+    }
+}
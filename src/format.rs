@@ -0,0 +1,49 @@
+use crate::{
+    error::Error,
+    lexer::{self, Token, COMMAND_END, COMMAND_FLAG, COMMAND_START},
+    library::get_filepaths_recursive,
+};
+use std::{fs, path::PathBuf};
+
+/// Normalizes the spacing inside every command, leaving content untouched.
+pub fn format(source: &str) -> String {
+    let mut output = String::with_capacity(source.len());
+    for lexeme in lexer::lex(source) {
+        match lexeme.token {
+            Token::Command(command) => {
+                output.push(COMMAND_FLAG);
+                output.push(COMMAND_START);
+                output.push_str(&lexer::words(&command).join(" "));
+                output.push(COMMAND_END);
+            }
+            Token::Content(_) | Token::Comment(_) | Token::Raw(..) | Token::Unclosed(_) => {
+                output.push_str(&source[lexeme.range])
+            }
+        }
+    }
+    output
+}
+
+/// Formats every file under `dir`, returning the files that were not already
+/// formatted. With `check`, the files are listed but left as they are.
+pub fn format_dir(dir: &PathBuf, check: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut unformatted = Vec::new();
+    for path in get_filepaths_recursive(dir)? {
+        let original = fs::read_to_string(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let formatted = format(&original);
+        if formatted == original {
+            continue;
+        }
+        if !check {
+            fs::write(&path, formatted).map_err(|source| Error::Io {
+                path: path.clone(),
+                source,
+            })?;
+        }
+        unformatted.push(path);
+    }
+    Ok(unformatted)
+}
@@ -0,0 +1,516 @@
+use crate::error::{Position, Span};
+use std::ops::Range;
+
+pub(crate) const COMMAND_FLAG: char = '^';
+pub(crate) const COMMAND_START: char = '|';
+pub(crate) const COMMAND_END: char = '|';
+/// Passes the output of a use command through a filter.
+pub(crate) const PIPE: &str = "|>";
+pub(crate) const COMMENT_PREFIX: &str = "--";
+pub(crate) const COMMENT_COMMAND: &str = "comment";
+const END_COMMENT: &str = "^|endcomment|";
+pub(crate) const RAW_COMMAND: &str = "raw";
+const END_RAW: &str = "^|endraw|";
+/// Removes the whitespace on its side of a command, as in `^|-u name-|`.
+pub(crate) const TRIM_MARKER: char = '-';
+
+pub(crate) enum Token {
+    /// Text to be copied into a block, with any cancelled flags resolved.
+    Content(String),
+    /// The text between a command's delimiters.
+    Command(String),
+    /// A `^|-- note|` command, or a region of source between `^|comment|`
+    /// and `^|endcomment|`. A `^|-- note -|` comment trims after itself, but
+    /// can't trim before, since `^|--- note|` reads as a comment starting
+    /// with `-`.
+    Comment(Trim),
+    /// The source between `^|raw|` and `^|endraw|`, copied as written.
+    Raw(String, Trim),
+    /// A command or region that reaches the end of the source without being
+    /// closed, with the reason it is an error.
+    Unclosed(&'static str),
+}
+
+/// The trim markers on a region's starting command. A region is trimmed as
+/// one command, so `^|-raw-|` trims before `^|raw|` and after `^|endraw|`.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Trim {
+    pub before: bool,
+    pub after: bool,
+}
+
+/// A run of source that ends at a fixed marker rather than at a command.
+#[derive(Clone, Copy)]
+enum Region {
+    Comment,
+    Raw,
+}
+
+/// Where a literal argument sits in the text of a command.
+struct Literal {
+    /// The bytes between the delimiters.
+    contents: Range<usize>,
+    /// The byte just past the closing delimiter.
+    end: usize,
+    /// Whether escapes are left as written.
+    raw: bool,
+}
+
+pub(crate) struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+    /// The byte range of the lexeme in the source, including delimiters.
+    pub range: Range<usize>,
+}
+
+/// Splits source text into content and commands.
+pub(crate) fn lex(string: &str) -> Vec<Lexeme> {
+    enum State {
+        Content,
+        CommandFlag,
+        /// Inside a command, and inside a `(text)` literal if `parens` is set.
+        Command {
+            parens: bool,
+        },
+        CommandEscape {
+            parens: bool,
+        },
+        Literal {
+            end: usize,
+        },
+        /// A region that ends at byte `end`, or runs to the end of the source.
+        Region {
+            region: Region,
+            trim: Trim,
+            end: Option<usize>,
+        },
+        CancelledFlag,
+    }
+    fn push_to_state(buffer: &mut String, c: char, state: State) -> State {
+        buffer.push(c);
+        state
+    }
+
+    let mut lexemes = Vec::new();
+    let mut position = Position::new(1, 1);
+    let mut state = State::Content;
+    let mut buffer = String::new();
+    let mut start = (position, 0);
+    let mut flag = (position, 0);
+    let mut last = position;
+    let mut content_end = position;
+    for (i, c) in string.char_indices() {
+        let current = position;
+        match c {
+            '\n' => position = Position::new(position.line + 1, 1),
+            _ => position.col += 1,
+        }
+        state = match state {
+            State::Content => match c {
+                COMMAND_FLAG => {
+                    flag = (current, i);
+                    content_end = last;
+                    State::CommandFlag
+                }
+                _ => push_to_state(&mut buffer, c, State::Content),
+            },
+            State::CommandFlag => match c {
+                COMMAND_START => {
+                    if flag.1 > start.1 {
+                        lexemes.push(Lexeme {
+                            token: Token::Content(std::mem::take(&mut buffer)),
+                            span: Span::new(start.0, content_end),
+                            range: start.1..flag.1,
+                        });
+                    }
+                    start = flag;
+                    State::Command { parens: false }
+                }
+                COMMAND_FLAG => push_to_state(&mut buffer, c, State::CancelledFlag),
+                _ => push_to_state(&mut buffer, c, State::Content),
+            },
+            State::Command { parens } => match c {
+                '\\' => push_to_state(&mut buffer, c, State::CommandEscape { parens }),
+                // quotes inside `(text)` are part of its text
+                '(' if !parens => push_to_state(&mut buffer, c, State::Command { parens: true }),
+                ')' if parens => push_to_state(&mut buffer, c, State::Command { parens: false }),
+                // a pipe after whitespace doesn't end the command, so `^|u x|>`
+                // is still a command followed by `>`
+                COMMAND_END
+                    if !parens
+                        && buffer.ends_with(char::is_whitespace)
+                        && string[i..].starts_with(PIPE) =>
+                {
+                    push_to_state(&mut buffer, c, State::Command { parens })
+                }
+                // quoted literals may hold the command delimiters
+                '"' | 'r'
+                    if !parens
+                        && (buffer.is_empty()
+                            || buffer.ends_with(char::is_whitespace)
+                            || buffer.ends_with('=')) =>
+                {
+                    match scan_literal(&string[i..]) {
+                        Some(literal) => push_to_state(
+                            &mut buffer,
+                            c,
+                            State::Literal {
+                                end: i + literal.end,
+                            },
+                        ),
+                        None => push_to_state(&mut buffer, c, State::Command { parens }),
+                    }
+                }
+                COMMAND_END => {
+                    let command = std::mem::take(&mut buffer);
+                    let after = i + c.len_utf8();
+                    match Region::starting(&command) {
+                        Some((region, trim)) => {
+                            let end = string[after..]
+                                .find(region.end_marker())
+                                .map(|offset| after + offset + region.end_marker().len());
+                            State::Region { region, trim, end }
+                        }
+                        None => {
+                            let token = match command.trim_start().strip_prefix(COMMENT_PREFIX) {
+                                Some(note) => Token::Comment(comment_trim(note)),
+                                None => Token::Command(command),
+                            };
+                            lexemes.push(Lexeme {
+                                token,
+                                span: Span::new(start.0, current),
+                                range: start.1..after,
+                            });
+                            start = (position, after);
+                            State::Content
+                        }
+                    }
+                }
+                _ => push_to_state(&mut buffer, c, State::Command { parens }),
+            },
+            State::CommandEscape { parens } => {
+                push_to_state(&mut buffer, c, State::Command { parens })
+            }
+            State::Literal { end } => match i + c.len_utf8() < end {
+                true => push_to_state(&mut buffer, c, State::Literal { end }),
+                false => push_to_state(&mut buffer, c, State::Command { parens: false }),
+            },
+            State::Region { region, trim, end } => {
+                buffer.push(c);
+                match end {
+                    Some(end) if i + c.len_utf8() == end => {
+                        buffer.truncate(buffer.len() - region.end_marker().len());
+                        lexemes.push(Lexeme {
+                            token: region.token(std::mem::take(&mut buffer), trim),
+                            span: Span::new(start.0, current),
+                            range: start.1..end,
+                        });
+                        start = (position, end);
+                        State::Content
+                    }
+                    _ => State::Region { region, trim, end },
+                }
+            }
+            State::CancelledFlag => match c {
+                COMMAND_FLAG => push_to_state(&mut buffer, c, State::CancelledFlag),
+                _ => push_to_state(&mut buffer, c, State::Content),
+            },
+        };
+        last = current;
+    }
+
+    if string.len() > start.1 {
+        let token = match state {
+            State::Command { .. } | State::CommandEscape { .. } | State::Literal { .. } => {
+                Token::Unclosed("Command is never closed")
+            }
+            State::Region { region, .. } => Token::Unclosed(region.unclosed()),
+            _ => Token::Content(buffer),
+        };
+        lexemes.push(Lexeme {
+            token,
+            span: Span::new(start.0, last),
+            range: start.1..string.len(),
+        });
+    }
+    lexemes
+}
+
+impl Region {
+    /// The region a command starts, if any, and the command's trim markers.
+    fn starting(command: &str) -> Option<(Region, Trim)> {
+        let (trim, command) = trim_markers(command);
+        match command.trim() {
+            COMMENT_COMMAND => Some((Region::Comment, trim)),
+            RAW_COMMAND => Some((Region::Raw, trim)),
+            _ => None,
+        }
+    }
+
+    fn end_marker(self) -> &'static str {
+        match self {
+            Region::Comment => END_COMMENT,
+            Region::Raw => END_RAW,
+        }
+    }
+
+    fn token(self, contents: String, trim: Trim) -> Token {
+        match self {
+            Region::Comment => Token::Comment(trim),
+            Region::Raw => Token::Raw(contents, trim),
+        }
+    }
+
+    fn unclosed(self) -> &'static str {
+        match self {
+            Region::Comment => "Comment is never ended",
+            Region::Raw => "Raw region is never ended",
+        }
+    }
+}
+
+/// Strips the trim markers from either end of a command.
+pub(crate) fn trim_markers(command: &str) -> (Trim, &str) {
+    let mut trim = Trim::default();
+    let mut command = command;
+    if let Some(rest) = command.strip_prefix(TRIM_MARKER) {
+        trim.before = true;
+        command = rest;
+    }
+    if let Some(rest) = command.strip_suffix(TRIM_MARKER) {
+        trim.after = true;
+        command = rest;
+    }
+    (trim, command)
+}
+
+/// The trim markers on a `^|-- note|` comment, given the text after its
+/// prefix. Comments made only of dashes, like `^|------|`, don't trim.
+fn comment_trim(note: &str) -> Trim {
+    Trim {
+        before: false,
+        after: note.ends_with(TRIM_MARKER) && !note.trim().chars().all(|c| c == TRIM_MARKER),
+    }
+}
+
+/// Splits a command into its flag and arguments, keeping each one as written.
+pub(crate) fn words(command: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    while let Some(offset) = command[start..].find(|c: char| !c.is_whitespace()) {
+        start += offset;
+        let end = match scan_literal(&command[start..]) {
+            // a word that starts with a literal ends with it
+            Some(literal) => start + literal.end,
+            None if command[..start].ends_with(char::is_whitespace)
+                && command[start..].starts_with(PIPE) =>
+            {
+                start + PIPE.len()
+            }
+            None => {
+                let mut end = start;
+                while let Some(c) = command[end..].chars().next() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    // a literal inside a word, as in `name=(text)`, is kept whole
+                    let literal = match c == '(' || command[..end].ends_with('=') {
+                        true => scan_literal(&command[end..]),
+                        false => None,
+                    };
+                    end += literal.map_or(c.len_utf8(), |literal| literal.end);
+                }
+                end
+            }
+        };
+        words.push(&command[start..end]);
+        start = end;
+    }
+    words
+}
+
+/// The text of a literal argument with its escapes resolved, if the word
+/// is one: `(text)`, `"text"`, or a raw `r"text"` or `r#"text"#`.
+pub(crate) fn literal(word: &str) -> Option<String> {
+    let literal = scan_literal(word)?;
+    let contents = &word[literal.contents];
+    match literal.raw {
+        true => Some(contents.to_string()),
+        false => Some(unescape(contents)),
+    }
+}
+
+/// Finds the literal at the start of `text`. An unterminated literal runs
+/// to the end of the text.
+fn scan_literal(text: &str) -> Option<Literal> {
+    let close = match text.chars().next()? {
+        '(' => ')',
+        '"' => '"',
+        'r' => return scan_raw_literal(text),
+        _ => return None,
+    };
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c == close => {
+                return Some(Literal {
+                    contents: 1..i,
+                    end: i + c.len_utf8(),
+                    raw: false,
+                })
+            }
+            _ => {}
+        }
+    }
+    Some(Literal {
+        contents: 1..text.len(),
+        end: text.len(),
+        raw: false,
+    })
+}
+
+fn scan_raw_literal(text: &str) -> Option<Literal> {
+    let hashes = text[1..].chars().take_while(|c| *c == '#').count();
+    if !text[1 + hashes..].starts_with('"') {
+        return None;
+    }
+    let start = hashes + 2;
+    let closing = format!("\"{}", "#".repeat(hashes));
+    Some(match text[start..].find(&closing) {
+        Some(i) => Literal {
+            contents: start..start + i,
+            end: start + i + closing.len(),
+            raw: true,
+        },
+        None => Literal {
+            contents: start..text.len(),
+            end: text.len(),
+            raw: true,
+        },
+    })
+}
+
+/// Resolves `\n`, `\\`, and escaped delimiters. Any other backslash is kept.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c @ ('\\' | '(' | ')' | '|' | '"')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<String> {
+        lex(source)
+            .into_iter()
+            .map(|lexeme| match lexeme.token {
+                Token::Content(content) => format!("content {:?}", content),
+                Token::Command(command) => format!("command {:?}", command),
+                Token::Comment(_) => String::from("comment"),
+                Token::Raw(raw, _) => format!("raw {:?}", raw),
+                Token::Unclosed(reason) => format!("unclosed {:?}", reason),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pipe_after_whitespace_continues_command() {
+        assert_eq!(tokens("^|u x |> upper|"), vec![r#"command "u x |> upper""#]);
+        assert_eq!(words("u x |> upper"), vec!["u", "x", "|>", "upper"]);
+    }
+
+    #[test]
+    fn quote_inside_parens_is_text() {
+        assert_eq!(
+            tokens("^|u card (a \" b)|<a href=\"x\">"),
+            vec![
+                r#"command "u card (a \" b)""#,
+                r#"content "<a href=\"x\">""#
+            ]
+        );
+        assert_eq!(literal("(a \" b)").as_deref(), Some("a \" b"));
+    }
+
+    #[test]
+    fn parens_inside_quotes_are_text() {
+        assert_eq!(
+            tokens("^|u card \"a ( | b\" (c)|"),
+            vec![r#"command "u card \"a ( | b\" (c)""#]
+        );
+        assert_eq!(
+            words("u card \"a ( | b\" (c) x=\")\" r\"(\""),
+            vec!["u", "card", "\"a ( | b\"", "(c)", "x=\")\"", "r\"(\""]
+        );
+    }
+
+    #[test]
+    fn escaped_delimiters_stay_in_literals() {
+        assert_eq!(
+            tokens("^|u x (a \\) \\| \") b|"),
+            vec![r#"command "u x (a \\) \\| \") b""#]
+        );
+        assert_eq!(literal("(a \\) \\| \")").as_deref(), Some("a ) | \""));
+    }
+
+    #[test]
+    fn regions_accept_trim_markers() {
+        let lexemes = lex("^|-raw-|x^|endraw|^|-comment|y^|endcomment|");
+        match &lexemes[..] {
+            [Lexeme {
+                token: Token::Raw(raw, raw_trim),
+                ..
+            }, Lexeme {
+                token: Token::Comment(comment_trim),
+                ..
+            }] => {
+                assert_eq!(raw, "x");
+                assert!(raw_trim.before && raw_trim.after);
+                assert!(comment_trim.before && !comment_trim.after);
+            }
+            _ => panic!("expected a raw region and a comment"),
+        }
+    }
+
+    #[test]
+    fn line_comments_trim_after() {
+        let trims: Vec<bool> = lex("^|-- note -|^|-- note|^|------|^|--|")
+            .into_iter()
+            .map(|lexeme| match lexeme.token {
+                Token::Comment(trim) => trim.after,
+                _ => panic!("expected only comments"),
+            })
+            .collect();
+        assert_eq!(trims, vec![true, false, false, false]);
+    }
+
+    #[test]
+    fn pipe_after_word_ends_command() {
+        assert_eq!(
+            tokens("<div ^|u x|>hi</div>"),
+            vec![
+                r#"content "<div ""#,
+                r#"command "u x""#,
+                r#"content ">hi</div>""#
+            ]
+        );
+    }
+}
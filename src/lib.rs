@@ -1,4 +1,14 @@
-pub use library::Library;
+pub use directive::{Contribution, Directive};
+pub use error::{Error, Location, Note, Position, Span};
+pub use format::{format, format_dir};
+pub use library::{Arg, BlockInfo, Generation, Indent, Library, Options, Value};
 
 mod block;
+mod builtin;
+pub mod diagnostic;
+pub mod directive;
+pub mod error;
+mod filter;
+mod format;
+mod lexer;
 pub mod library;
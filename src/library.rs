@@ -1,48 +1,192 @@
-use crate::block::{Block, Export};
-use std::{collections::HashMap, fs, path::PathBuf};
+use crate::{
+    block::{Block, Context, Export, FileExport, Parameter},
+    builtin,
+    directive::Directive,
+    error::{Error, Location},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+const DEFAULT_GENERATION_LIMIT: usize = 32;
+
+/// Settings that change how a library imports and exports blocks.
+pub struct Options {
+    /// How many passes of block exports may run before giving up.
+    pub generation_limit: usize,
+    /// A directory to write the source produced by each block export pass.
+    pub dump_generated: Option<PathBuf>,
+    /// Report source that would otherwise be silently ignored.
+    pub strict: bool,
+    /// The indentation of files that don't set their own with `^|indent|`.
+    pub indent: Indent,
+}
+
+/// The whitespace `ui` measures and adds for each level of indentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Indent {
+    #[default]
+    Tabs,
+    /// Each level is this many spaces.
+    Spaces(usize),
+}
+
+/// The blocks imported from rendering one block export.
+#[derive(Clone, Debug)]
+pub struct Generation {
+    pub block: String,
+    pub generated: Vec<String>,
+}
+
+/// A value passed to a block's param when rendering it from Rust.
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// Text inserted as written, like `(text)` in a use command.
+    Literal(String),
+    /// The name of a block in the library, rendered where the param is used.
+    Block(String),
+}
+
+/// An argument passed to a block when rendering it from Rust.
+#[derive(Clone, Debug)]
+pub enum Arg {
+    /// Bound to the next param in the order they are declared.
+    Positional(Value),
+    /// Bound to the param with this name.
+    Named(String, Value),
+}
+
+/// A read-only view of a block in a library.
+pub struct BlockInfo<'a> {
+    block: &'a Block,
+}
 
 pub struct Library {
+    options: Options,
+    warnings: Vec<Error>,
     blocks: HashMap<String, Block>,
     block_exports: Vec<String>,
-    file_exports: HashMap<String, PathBuf>,
+    file_exports: Vec<String>,
+    directives: HashMap<String, Box<dyn Directive>>,
+    /// The time stamped by built-in blocks, fixed for the whole build.
+    time: u64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            generation_limit: DEFAULT_GENERATION_LIMIT,
+            dump_generated: None,
+            strict: false,
+            indent: Indent::Tabs,
+        }
+    }
+}
+
+impl Default for Library {
+    fn default() -> Library {
+        Library::new()
+    }
 }
 
 impl Library {
     pub fn new() -> Library {
+        Library::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Library {
         Library {
+            options,
+            warnings: Vec::new(),
             blocks: HashMap::new(),
             block_exports: Vec::new(),
-            file_exports: HashMap::new(),
+            file_exports: Vec::new(),
+            directives: HashMap::new(),
+            time: builtin::build_time(),
         }
     }
 
-    pub fn new_from_dir(dir: &PathBuf) -> Result<Library, String> {
+    pub fn new_from_dir(dir: &PathBuf) -> Result<Library, Error> {
         let mut library = Library::new();
-        for path in get_filepaths_recursive(dir) {
-            library.import_from_file(&path)?;
-        }
+        library.import_from_dir(dir)?;
         Ok(library)
     }
 
-    pub fn import_from_file(&mut self, path: &PathBuf) -> Result<(), String> {
-        fn display(path: &PathBuf) -> &str {
-            match path.to_str() {
-                Some(s) => s,
-                None => "<invalid path>",
+    pub fn import_from_dir(&mut self, dir: &PathBuf) -> Result<(), Error> {
+        let mut errors = Vec::new();
+        for path in get_filepaths_recursive(dir)? {
+            let source = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+            if let Err(e) = self.import_source_file(&path, source) {
+                errors.push(e);
             }
         }
+        if errors.is_empty() {
+            errors.append(&mut self.find_cycles());
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.into()),
+        }
+    }
 
-        let file = fs::read_to_string(&path)
-            .map_err(|e| format!("File read error for '{}': {}", display(path), e))?;
+    pub fn import_from_file(&mut self, path: &PathBuf) -> Result<(), Error> {
+        let source = path.file_name().map_or_else(PathBuf::new, PathBuf::from);
+        self.import_source_file(path, source)
+    }
 
-        self.import_from_string(&file)
-            .map_err(|e| format!("'{}': {}", display(path), e))
+    pub fn import_from_string(&mut self, string: &str) -> Result<(), Error> {
+        self.import(string, None, None)?;
+        Ok(())
+    }
+
+    /// Imports a file, recording `source` as the path blocks render for `@file`.
+    fn import_source_file(&mut self, path: &PathBuf, source: PathBuf) -> Result<(), Error> {
+        let file = fs::read_to_string(path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+
+        self.import(&file, Some(path), Some(source))?;
+        Ok(())
     }
 
-    pub fn import_from_string(&mut self, string: &str) -> Result<(), String> {
-        for block in Block::parse(string)? {
-            if self.blocks.contains_key(&block.name) {
-                return Err(format!("Import error: duplicate name '{}'", &block.name));
+    /// Imports every block in `string`, returning the names of the new blocks.
+    fn import(
+        &mut self,
+        string: &str,
+        path: Option<&Path>,
+        source: Option<PathBuf>,
+    ) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        let mut errors = Vec::new();
+        let mut lints = Vec::new();
+        let blocks = Block::parse(
+            string,
+            path,
+            self.options.indent,
+            &self.directives,
+            &mut lints,
+        )?;
+        for lint in lints {
+            match lint {
+                Error::MixedIndentation { .. } => self.warnings.push(lint),
+                _ if !self.options.strict => {}
+                Error::StrayContent { .. } | Error::UnusedParam { .. } => self.warnings.push(lint),
+                _ => errors.push(lint),
+            }
+        }
+        for mut block in blocks {
+            block.source.clone_from(&source);
+            if let Some(existing) = self.blocks.get(&block.name) {
+                errors.push(Error::DuplicateBlock {
+                    name: block.name,
+                    location: block.location,
+                    first: Box::new(existing.location.clone()),
+                });
+                continue;
             }
 
             if let Some(export) = &block.export {
@@ -50,89 +194,513 @@ impl Library {
                     Export::Block => {
                         self.block_exports.push(block.name.clone());
                     }
-                    Export::File(path) => {
-                        self.file_exports.insert(block.name.clone(), path.into());
+                    Export::File { .. } => {
+                        self.file_exports.push(block.name.clone());
                     }
                 }
             }
 
+            names.push(block.name.clone());
             self.blocks.insert(block.name.clone(), block);
         }
 
+        match errors.is_empty() {
+            true => Ok(names),
+            false => Err(errors.into()),
+        }
+    }
+
+    /// Adds a block whose content is computed by `render`, which receives
+    /// the block's arguments as text in the order of `params`. Blocks passed
+    /// as arguments are rendered before `render` is called.
+    pub fn register_native<F, E>(
+        &mut self,
+        name: &str,
+        params: &[&str],
+        render: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(&[String]) -> Result<String, E> + Send + Sync + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let native = Arc::new(move |args: &[String]| render(args).map_err(Into::into));
+        let block = Block::native(name, params, native, self.options.indent)?;
+        if let Some(existing) = self.blocks.get(name) {
+            return Err(Error::DuplicateBlock {
+                name: block.name,
+                location: block.location,
+                first: Box::new(existing.location.clone()),
+            });
+        }
+        self.blocks.insert(block.name.clone(), block);
         Ok(())
     }
 
-    pub fn render(&self, name: &str) -> Result<String, String> {
+    /// Adds a custom command, used as `^|flag arguments|` in source imported
+    /// after it is registered.
+    pub fn register_directive(
+        &mut self,
+        flag: &str,
+        directive: impl Directive + 'static,
+    ) -> Result<(), Error> {
+        let reason = match Block::reserved_flag(flag) {
+            Some(reason) => Some(reason),
+            None if self.directives.contains_key(flag) => Some("it is already registered"),
+            None => None,
+        };
+        if let Some(reason) = reason {
+            return Err(Error::InvalidDirective {
+                flag: flag.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+        self.directives
+            .insert(flag.to_string(), Box::new(directive));
+        Ok(())
+    }
+
+    /// Every block in the library, sorted by name.
+    pub fn blocks(&self) -> Vec<BlockInfo<'_>> {
+        let mut blocks: Vec<BlockInfo> = self
+            .blocks
+            .values()
+            .map(|block| BlockInfo { block })
+            .collect();
+        blocks.sort_by(|a, b| a.name().cmp(b.name()));
+        blocks
+    }
+
+    /// Problems found in strict mode that did not stop the import.
+    pub fn warnings(&self) -> &[Error] {
+        &self.warnings
+    }
+
+    pub fn render(&self, name: &str) -> Result<String, Error> {
+        self.render_with(name, &[])
+    }
+
+    /// Renders a block, passing each literal to its params in order.
+    pub fn render_with_literals(&self, name: &str, literals: &[String]) -> Result<String, Error> {
+        let args: Vec<Arg> = literals.iter().map(Arg::literal).collect();
+        self.render_with(name, &args)
+    }
+
+    /// Renders a block with arguments for its params. Positional arguments
+    /// are bound first, in order, and named arguments fill the rest.
+    pub fn render_with(&self, name: &str, args: &[Arg]) -> Result<String, Error> {
+        let mut positional = Vec::new();
+        let mut named = Vec::new();
+        for arg in args {
+            match arg {
+                Arg::Positional(value) => positional.push(value.into()),
+                Arg::Named(name, value) => named.push((name.clone(), value.into())),
+            }
+        }
+        self.render_parameters(name, positional, named, None)
+    }
+
+    fn render_parameters(
+        &self,
+        name: &str,
+        positional: Vec<Parameter>,
+        named: Vec<(String, Parameter)>,
+        export: Option<&str>,
+    ) -> Result<String, Error> {
         let block = match self.blocks.get(name) {
             Some(b) => b,
-            None => return Err(format!("Render Error: block '{}' not found", name)),
+            None => {
+                return Err(Error::UnknownBlock {
+                    name: name.into(),
+                    location: None,
+                })
+            }
+        };
+        let context = Context {
+            blocks: &self.blocks,
+            export,
+            time: self.time,
         };
         let render = block
-            .render(&self.blocks)
-            .map_err(|e| format!("Render Error for block '{}': {}", name, e))?;
+            .render(&context, positional, named)
+            .map_err(|e| Error::Render {
+                block: name.into(),
+                source: Box::new(e),
+            })?;
         Ok(render)
     }
 
-    pub fn export_all(&mut self, dir: &PathBuf, verbose: bool) -> Result<(), String> {
+    /// Checks every block and file export without rendering or writing anything.
+    pub fn check(&self) -> Result<(), Error> {
+        let mut names: Vec<&String> = self.blocks.keys().collect();
+        names.sort();
+        let mut errors = Vec::new();
+        for name in &names {
+            errors.append(&mut self.blocks[*name].check(&self.blocks));
+        }
+
+        let mut exported: HashMap<PathBuf, &Block> = HashMap::new();
+        for name in names {
+            let block = &self.blocks[name];
+            for export in block.file_exports() {
+                let export = match export {
+                    Ok(export) => export,
+                    // undeclared params were reported by `Block::check`
+                    Err(Error::MissingParam { .. }) => continue,
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                };
+                let location = Location::new(block.location.path.clone(), export.span);
+                for parameter in &export.parameters {
+                    if let Parameter::Name(name) = parameter {
+                        if !self.blocks.contains_key(name) && !builtin::exists(name) {
+                            errors.push(Error::UnknownBlock {
+                                name: name.clone(),
+                                location: Some(location.clone()),
+                            });
+                        }
+                    }
+                }
+                let invalid = |reason: &str| Error::InvalidExport {
+                    path: export.path.clone(),
+                    reason: reason.into(),
+                    location: location.clone(),
+                };
+                match export_path(&export.path) {
+                    Err(reason) => errors.push(invalid(reason)),
+                    Ok(normalized) => match exported.get(&normalized) {
+                        Some(other) => errors.push(invalid(&format!(
+                            "the path is also exported by block '{}'",
+                            other.name
+                        ))),
+                        None => {
+                            exported.insert(normalized, block);
+                        }
+                    },
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.into()),
+        }
+    }
+
+    /// Renders block exports and imports their output until no new block
+    /// exports are generated.
+    pub fn generate(&mut self) -> Result<(), Error> {
+        let mut passes: Vec<Vec<Generation>> = Vec::new();
         loop {
-            // clone list of block exports and ingest
-            let block_exports = self.block_exports.clone();
-            self.block_exports.clear();
+            // if we haven't made more block exports, exit
+            if self.block_exports.is_empty() {
+                break;
+            }
+            if passes.len() == self.options.generation_limit {
+                return Err(Error::GenerationLimit {
+                    limit: self.options.generation_limit,
+                    passes,
+                });
+            }
+
+            // take list of block exports and ingest
+            let pass = passes.len() + 1;
+            let block_exports = std::mem::take(&mut self.block_exports);
+            let mut generations = Vec::new();
             for block_name in block_exports {
                 let render = self.render(&block_name)?;
-                self.import_from_string(&render)?;
+                let dump_path = self.dump_generated(pass, &block_name, &render)?;
+                let generated = self
+                    .import(&render, dump_path.as_deref(), None)
+                    .map_err(|e| {
+                        let errors: Vec<Error> = e
+                            .into_errors()
+                            .into_iter()
+                            .map(|e| Error::Generated {
+                                block: block_name.clone(),
+                                pass,
+                                source: Box::new(e),
+                            })
+                            .collect();
+                        Error::from(errors)
+                    })?;
+                generations.push(Generation {
+                    block: block_name,
+                    generated,
+                });
             }
+            passes.push(generations);
+        }
+        Ok(())
+    }
 
-            // if we haven't made more block exports, exit
-            match self.block_exports.is_empty() {
-                true => break,
-                false => continue,
+    pub fn export_all(&mut self, dir: &Path, verbose: bool) -> Result<(), Error> {
+        self.generate()?;
+
+        for block_name in &self.file_exports {
+            for export in self.blocks[block_name].file_exports() {
+                let export = export?;
+                self.export_file(dir, block_name, export, verbose)?;
             }
         }
+        Ok(())
+    }
 
-        for (block_name, file_path) in &self.file_exports {
-            let render = self.render(block_name)?;
-            let path = build_path(&dir, &file_path);
-            std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| format!("{}", e))?;
-            fs::write(&path, &render).map_err(|e| format!("{}: {}", block_name, e))?;
+    fn export_file(
+        &self,
+        dir: &Path,
+        block_name: &str,
+        export: FileExport,
+        verbose: bool,
+    ) -> Result<(), Error> {
+        // argument sets fill path templates, so check the path that was filled
+        let relative = export_path(&export.path).map_err(|reason| Error::InvalidExport {
+            path: export.path.clone(),
+            reason: reason.into(),
+            location: Location::new(self.blocks[block_name].location.path.clone(), export.span),
+        })?;
+        let render = self.render_parameters(
+            block_name,
+            export.parameters,
+            Vec::new(),
+            Some(&export.path),
+        )?;
+        let path = build_path(dir, &relative);
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent).map_err(|source| Error::Io {
+            path: parent.to_path_buf(),
+            source,
+        })?;
+        fs::write(&path, &render).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
 
-            if verbose {
-                println!(
-                    "Exported block '{}' ({}B) to '{}'",
-                    &block_name,
-                    render.as_bytes().len(),
-                    path.to_string_lossy()
-                );
-            }
+        if verbose {
+            println!(
+                "Exported block '{}' ({}B) to '{}'",
+                &block_name,
+                render.len(),
+                path.to_string_lossy()
+            );
         }
         Ok(())
     }
+
+    /// Writes the source generated by a block export, if dumping is enabled.
+    fn dump_generated(
+        &self,
+        pass: usize,
+        block_name: &str,
+        source: &str,
+    ) -> Result<Option<PathBuf>, Error> {
+        let dir = match &self.options.dump_generated {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        // block names may hold `/`, so keep them inside the dump directory
+        let relative = format!("pass-{}/{}.txt", pass, block_name);
+        let relative = export_path(&relative).map_err(|reason| Error::InvalidExport {
+            path: relative.clone(),
+            reason: reason.into(),
+            location: self.blocks[block_name].location.clone(),
+        })?;
+        let path = build_path(dir, &relative);
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent).map_err(|source| Error::Io {
+            path: parent.to_path_buf(),
+            source,
+        })?;
+        fs::write(&path, source).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        Ok(Some(path))
+    }
+
+    /// Finds every circular include that can be seen without rendering.
+    fn find_cycles(&self) -> Vec<Error> {
+        fn visit<'a>(
+            library: &'a Library,
+            name: &'a str,
+            stack: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+            errors: &mut Vec<Error>,
+        ) {
+            let block = match library.blocks.get(name) {
+                Some(b) => b,
+                None => return,
+            };
+            stack.push(name);
+            for (dependency, span) in block.dependencies() {
+                if let Some(start) = stack.iter().position(|n| *n == dependency) {
+                    let mut chain: Vec<String> =
+                        stack[start..].iter().map(|n| n.to_string()).collect();
+                    chain.push(dependency.to_string());
+                    errors.push(Error::Cycle {
+                        chain,
+                        location: Some(Location::new(block.location.path.clone(), span)),
+                    });
+                } else if !done.contains(dependency) {
+                    visit(library, dependency, stack, done, errors);
+                }
+            }
+            stack.pop();
+            done.insert(name);
+        }
+
+        let mut names: Vec<&str> = self.blocks.keys().map(String::as_str).collect();
+        names.sort();
+        let mut errors = Vec::new();
+        let mut done = HashSet::new();
+        for name in names {
+            if !done.contains(name) {
+                visit(self, name, &mut Vec::new(), &mut done, &mut errors);
+            }
+        }
+        errors
+    }
+}
+
+impl Indent {
+    /// The whitespace for one level of indentation.
+    pub(crate) fn unit(self) -> String {
+        match self {
+            Indent::Tabs => String::from("\t"),
+            Indent::Spaces(width) => " ".repeat(width),
+        }
+    }
+
+    /// How many levels of indentation a line starts with. A tab is always
+    /// one level.
+    pub(crate) fn levels(self, line: &str) -> usize {
+        let mut tabs = 0;
+        let mut spaces = 0;
+        for c in line.chars() {
+            match c {
+                '\t' => tabs += 1,
+                ' ' => spaces += 1,
+                _ => break,
+            }
+        }
+        match self {
+            Indent::Tabs => tabs,
+            Indent::Spaces(width) => tabs + spaces / width.max(1),
+        }
+    }
+}
+
+impl Arg {
+    pub fn literal(text: impl Into<String>) -> Arg {
+        Arg::Positional(Value::Literal(text.into()))
+    }
+
+    pub fn block(name: impl Into<String>) -> Arg {
+        Arg::Positional(Value::Block(name.into()))
+    }
+
+    pub fn named(name: impl Into<String>, value: Value) -> Arg {
+        Arg::Named(name.into(), value)
+    }
+}
+
+impl From<&Value> for Parameter {
+    fn from(value: &Value) -> Parameter {
+        match value {
+            Value::Literal(text) => Parameter::Literal(text.clone()),
+            Value::Block(name) => Parameter::Name(name.clone()),
+        }
+    }
+}
+
+impl<'a> BlockInfo<'a> {
+    pub fn name(&self) -> &'a str {
+        &self.block.name
+    }
+
+    pub fn params(&self) -> Vec<&'a str> {
+        self.block.params.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    /// Where the block was defined.
+    pub fn location(&self) -> &'a Location {
+        &self.block.location
+    }
+
+    /// The path this block is exported to, if it is a file export.
+    pub fn file_export(&self) -> Option<&'a str> {
+        match &self.block.export {
+            Some(Export::File { path, .. }) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Whether this block is rendered by a Rust function.
+    pub fn is_native(&self) -> bool {
+        self.block.native.is_some()
+    }
+
+    /// Whether this block is rendered and re-imported as more blocks.
+    pub fn is_block_export(&self) -> bool {
+        matches!(self.block.export, Some(Export::Block))
+    }
+
+    /// Names of the blocks this block always uses.
+    pub fn dependencies(&self) -> Vec<&'a str> {
+        let mut dependencies: Vec<&str> = Vec::new();
+        for (name, _) in self.block.dependencies() {
+            if !dependencies.contains(&name) {
+                dependencies.push(name);
+            }
+        }
+        dependencies
+    }
+}
+
+/// The normalized form of a file export path, or why it can't be written.
+fn export_path(path: &str) -> Result<PathBuf, &'static str> {
+    let components = Path::new(path).components();
+    if components
+        .clone()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err("the path must stay inside the output directory");
+    }
+    let normalized: PathBuf = components.collect();
+    match normalized.as_os_str().is_empty() {
+        true => Err("the path is empty"),
+        false => Ok(normalized),
+    }
 }
 
-fn build_path(base: &PathBuf, append: &PathBuf) -> PathBuf {
-    let mut path = base.clone();
+fn build_path(base: &Path, append: &Path) -> PathBuf {
+    let mut path = base.to_path_buf();
     path.push(append);
     path
 }
 
-fn get_filepaths_recursive(dir: &PathBuf) -> Vec<PathBuf> {
-    match fs::read_dir(dir) {
-        Ok(dir) => dir
-            .filter_map(|e| e.ok())
-            .filter_map(|e| match e.metadata() {
-                Ok(meta) => {
-                    if meta.is_dir() {
-                        Some(get_filepaths_recursive(&e.path()))
-                    } else if meta.is_file() {
-                        Some(vec![e.path()])
-                    } else {
-                        None
-                    }
-                }
-                Err(_) => None,
-            })
-            .flat_map(|paths| paths.into_iter())
-            .collect(),
-        Err(_) => Vec::new(),
+/// Every file under `dir`, sorted so that imports and their errors come in
+/// the same order on every run.
+pub(crate) fn get_filepaths_recursive(dir: &PathBuf) -> Result<Vec<PathBuf>, Error> {
+    let io_error = |source| Error::Io {
+        path: dir.clone(),
+        source,
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        let path = entry.path();
+        let meta = entry.metadata().map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        if meta.is_dir() {
+            paths.append(&mut get_filepaths_recursive(&path)?);
+        } else if meta.is_file() {
+            paths.push(path);
+        }
     }
+    paths.sort();
+    Ok(paths)
 }